@@ -0,0 +1,1214 @@
+use crate::format::format_duration;
+use crate::helper::is_url;
+use crate::{Action, EqPreset, RepeatMode, SeekPosition, TrackOptions};
+use log::info;
+use std::collections::VecDeque;
+use tokio::time::{Duration, Instant};
+use tsclientlib::{ClientId, MessageTarget};
+
+fn sanitize(s: &str) -> String {
+    s.chars()
+        .filter(|c| {
+            c.is_alphanumeric()
+                || [
+                    ' ', '.', ' ', '=', '\t', ',', '?', '!', ':', '&', '/', '-', '_', '+',
+                ]
+                .contains(c)
+        })
+        .collect()
+}
+
+/// Links are kept verbatim so query parameters, fragments and percent-encoding survive,
+/// everything else goes through `sanitize`.
+fn sanitize_argument(arg: &str) -> String {
+    if is_url(arg) {
+        arg.chars().filter(|c| !c.is_control()).collect()
+    } else {
+        sanitize(arg)
+    }
+}
+
+/// Splits trailing `vol=NN` (0 to 100), `start=SECS` and `end=SECS` off the arguments of
+/// `!play` and `!next`, in any order.
+fn split_track_options(args: &[&str]) -> (String, TrackOptions) {
+    let mut options = TrackOptions::default();
+    let mut query = args;
+    while let [rest @ .., last] = query {
+        if rest.is_empty() {
+            break;
+        }
+        let value = |prefix: &str| {
+            last.strip_prefix(prefix)
+                .and_then(|v| v.parse::<u32>().ok())
+        };
+        if let Some(percent) = value("vol=") {
+            options.volume = Some(percent.min(100) as f32 / 100.0);
+        } else if let Some(secs) = value("start=") {
+            options.start = Some(secs);
+        } else if let Some(secs) = value("end=") {
+            options.end = Some(secs);
+        } else {
+            break;
+        }
+        query = rest;
+    }
+    (query.join(" "), options)
+}
+
+/// Checks the clip of a track against itself and, if already known, the track's duration.
+pub fn validate_clip(options: &TrackOptions, duration: Option<u32>) -> Result<(), String> {
+    let start = options.start.unwrap_or(0);
+    if let Some(end) = options.end {
+        if end <= start {
+            return Err("The end of the clip must be after its start".to_string());
+        }
+    }
+    if let Some(duration) = duration {
+        if start >= duration {
+            return Err(format!(
+                "The clip starts after the end of the track ({})",
+                format_duration(duration)
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the BBCode links TeamSpeak wraps pasted links in with their target, in any case:
+/// `[URL]link[/URL]` becomes `link` and `[URL=href]title[/URL]` becomes `href`.
+fn strip_url_tags(msg: &str) -> String {
+    const OPEN: &str = "[url";
+    const CLOSE: &str = "[/url]";
+
+    let mut msg = msg.to_string();
+    let mut from = 0;
+    loop {
+        // ASCII lowercasing keeps the byte offsets of the original
+        let lower = msg.to_ascii_lowercase();
+        let start = match lower[from..].find(OPEN) {
+            Some(start) => from + start,
+            None => break,
+        };
+        let open_end = match lower[start..].find(']') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let attribute = &msg[start + OPEN.len()..open_end];
+        let href = match attribute.strip_prefix('=') {
+            Some(href) => Some(href.trim_matches('"').to_string()),
+            None if attribute.is_empty() => None,
+            // Something like `[urlfoo]` is not a tag
+            None => {
+                from = start + OPEN.len();
+                continue;
+            }
+        };
+
+        let (close_start, close_end) = match lower[open_end..].find(CLOSE) {
+            Some(close) => (open_end + close, open_end + close + CLOSE.len()),
+            None => (msg.len(), msg.len()),
+        };
+        let replacement = href.unwrap_or_else(|| msg[open_end + 1..close_start].to_string());
+        msg.replace_range(start..close_end, &replacement);
+        from = start;
+    }
+
+    // Closing tags left over from nested links
+    while let Some(start) = msg.to_ascii_lowercase().find(CLOSE) {
+        msg.replace_range(start..start + CLOSE.len(), "");
+    }
+    msg
+}
+
+/// Syntax of the commands that take arguments, without the configured prefix.
+fn command_usage(command: &str) -> Option<&'static str> {
+    let usage = match command {
+        "play" | "yt" => "!play <link|search> [vol=<0-100>] [start=<seconds>] [end=<seconds>]",
+        "next" | "n" => "!next <link|search> [vol=<0-100>] [start=<seconds>] [end=<seconds>]",
+        "playlocal" => "!playlocal <path>",
+        "search" => "!search <query>",
+        "pick" => "!pick <n>",
+        "seek" => "!seek <seconds>, !seek +<seconds> or !seek -<seconds>",
+        "remove" | "rm" => "!remove <n>",
+        "skipto" => "!skipto <n>",
+        "move" => "!move <from> <to>",
+        "load" => "!load <name>",
+        "save" => "!save <name>",
+        "volume" | "v" => "!volume <0-100>",
+        "loop" => "!loop <off|one|all>",
+        "eq" => "!eq <flat|bass|treble|vocal>",
+        "speed" => "!speed <0.5-2>",
+        "shuffle" => "!shuffle [on|off]",
+        "dj" => "!dj [off]",
+        "name" => "!name <name>",
+        _ => return None,
+    };
+    Some(usage)
+}
+
+/// A known command with bad arguments gets its usage as reply, anything else is no command.
+fn invalid_usage(command: &str, user_id: ClientId) -> Action {
+    command_usage(command).map_or(Action::None, |usage| {
+        Action::Usage(usage.to_string(), user_id)
+    })
+}
+
+/// Commands that affect everyone and are not accepted in the channel or server chat.
+fn is_private_only(action: &Action) -> bool {
+    matches!(action, Action::Quit | Action::Restart(_))
+}
+
+/// Private commands are answered privately, channel and server commands where they were sent.
+pub fn reply_target(target: MessageTarget, user_id: ClientId) -> MessageTarget {
+    match target {
+        MessageTarget::Channel | MessageTarget::Server => target,
+        _ => MessageTarget::Client(user_id),
+    }
+}
+
+/// Parses a chat message, `target` is where it was sent to.
+pub fn parse_command(msg: &str, user_id: ClientId, prefix: &str, target: MessageTarget) -> Action {
+    let action = parse_action(msg, user_id, prefix);
+    if is_private_only(&action) && !matches!(target, MessageTarget::Client(_)) {
+        info!("{:?} is only accepted in private messages", action);
+        return Action::PrivateOnly(user_id);
+    }
+    action
+}
+
+fn parse_action(msg: &str, user_id: ClientId, prefix: &str) -> Action {
+    let stripped = strip_url_tags(msg);
+    let mut words = stripped.split_whitespace();
+    // The prefix is stripped before sanitizing, so it may contain characters sanitize drops
+    let command = match words.next().and_then(|word| word.strip_prefix(prefix)) {
+        Some(command) => sanitize(command).to_lowercase(),
+        None => return Action::None,
+    };
+    if command.is_empty() {
+        return Action::None;
+    }
+
+    let arguments: Vec<String> = words
+        .map(sanitize_argument)
+        .filter(|arg| !arg.is_empty())
+        .collect();
+    let split_vec: Vec<&str> = std::iter::once(command.as_str())
+        .chain(arguments.iter().map(|arg| arg.as_str()))
+        .collect();
+
+    if split_vec[0] == "stop" {
+        info!("Stopping all tracks (requested by {})", user_id);
+        return Action::Stop;
+    }
+
+    if split_vec[0] == "clear" {
+        info!("Clearing queue (requested by {})", user_id);
+        return Action::ClearQueue(user_id);
+    }
+
+    if split_vec[0] == "pause" || split_vec[0] == "p" {
+        return Action::Pause;
+    }
+
+    if split_vec[0] == "toggle" || split_vec[0] == "t" {
+        return Action::TogglePause(user_id);
+    }
+
+    if split_vec[0] == "mute" || split_vec[0] == "stfu" {
+        return Action::SetMute(true, user_id);
+    }
+
+    if split_vec[0] == "unmute" {
+        return Action::SetMute(false, user_id);
+    }
+
+    if split_vec[0] == "quiet" {
+        return Action::ToggleQuiet(user_id);
+    }
+
+    if split_vec[0] == "dj" {
+        return match split_vec.get(1) {
+            None => Action::SetDj(true, user_id),
+            Some(&"off") => Action::SetDj(false, user_id),
+            Some(_) => invalid_usage("dj", user_id),
+        };
+    }
+
+    if split_vec[0] == "continue"
+        || split_vec[0] == "c"
+        || split_vec[0] == "resume"
+        || split_vec[0] == "r"
+    {
+        return Action::Resume;
+    }
+
+    if split_vec[0] == "next" || split_vec[0] == "n" {
+        if split_vec.len() > 1 {
+            let (query, options) = split_track_options(&split_vec[1..]);
+            info!("Queueing: {} (requested by {})", query, user_id);
+            return Action::QueueNextAudio(query, options, user_id);
+        }
+        return Action::Skip(user_id);
+    }
+
+    if split_vec[0] == "skip" || split_vec[0] == "s" {
+        return Action::Skip(user_id);
+    }
+
+    if split_vec[0] == "voteskip" {
+        return Action::VoteSkip(user_id);
+    }
+
+    if split_vec[0] == "join" {
+        return Action::Join(user_id);
+    }
+
+    if split_vec[0] == "leave" {
+        return Action::Leave(user_id);
+    }
+
+    if split_vec[0] == "prev" {
+        info!("Playing previous track (requested by {})", user_id);
+        return Action::PlayPrevious(user_id);
+    }
+
+    if split_vec[0] == "replay" {
+        info!("Replaying current track (requested by {})", user_id);
+        return Action::Replay(user_id);
+    }
+
+    if split_vec[0] == "help" || split_vec[0] == "h" {
+        return Action::Help(user_id);
+    }
+
+    if split_vec[0] == "info" || split_vec[0] == "i" {
+        return Action::Info(user_id);
+    }
+
+    if split_vec[0] == "ping" {
+        return Action::Ping(user_id);
+    }
+
+    if split_vec[0] == "np" {
+        return Action::NowPlaying(user_id);
+    }
+
+    if split_vec[0] == "queue" || split_vec[0] == "ql" {
+        return Action::ShowQueue(user_id);
+    }
+
+    if split_vec[0] == "history" {
+        return Action::ShowHistory(user_id);
+    }
+
+    if split_vec[0] == "loop" {
+        if split_vec.len() < 2 {
+            return Action::SetRepeatMode(None, user_id);
+        }
+        let mode = match split_vec[1] {
+            "off" => RepeatMode::Off,
+            "one" => RepeatMode::One,
+            "all" => RepeatMode::All,
+            _ => return invalid_usage("loop", user_id),
+        };
+        info!(
+            "Setting repeat mode to {} (requested by {})",
+            mode.as_str(),
+            user_id
+        );
+        return Action::SetRepeatMode(Some(mode), user_id);
+    }
+
+    if split_vec[0] == "eq" {
+        if split_vec.len() < 2 {
+            return Action::SetEq(None, user_id);
+        }
+        let preset = match split_vec[1] {
+            "flat" => EqPreset::Flat,
+            "bass" => EqPreset::Bass,
+            "treble" => EqPreset::Treble,
+            "vocal" => EqPreset::Vocal,
+            _ => return invalid_usage("eq", user_id),
+        };
+        info!(
+            "Setting equalizer to {} (requested by {})",
+            preset.as_str(),
+            user_id
+        );
+        return Action::SetEq(Some(preset), user_id);
+    }
+
+    if split_vec[0] == "speed" {
+        if split_vec.len() < 2 {
+            return Action::SetSpeed(None, user_id);
+        }
+        return match split_vec[1].trim_end_matches('x').parse::<f32>() {
+            Ok(speed) if speed.is_finite() => {
+                info!("Setting speed to {} (requested by {})", speed, user_id);
+                Action::SetSpeed(Some(speed), user_id)
+            }
+            _ => invalid_usage("speed", user_id),
+        };
+    }
+
+    if split_vec[0] == "shuffle" {
+        if split_vec.len() < 2 {
+            info!("Shuffling queue (requested by {})", user_id);
+            return Action::Shuffle(user_id);
+        }
+        return match split_vec[1] {
+            "on" => Action::SetShuffle(true, user_id),
+            "off" => Action::SetShuffle(false, user_id),
+            _ => invalid_usage("shuffle", user_id),
+        };
+    }
+
+    if split_vec[0] == "restart" {
+        info!("Restarting the connection (requested by {})", user_id);
+        return Action::Restart(user_id);
+    }
+
+    if split_vec[0] == "quit" || split_vec[0] == "q" {
+        info!("Quitting (requested by {})", user_id);
+        return Action::Quit;
+    }
+
+    if split_vec[0] == "volup" || split_vec[0] == "vu" {
+        return Action::AdjustVolume {
+            direction: 1,
+            user_id,
+        };
+    }
+
+    if split_vec[0] == "voldown" || split_vec[0] == "vd" {
+        return Action::AdjustVolume {
+            direction: -1,
+            user_id,
+        };
+    }
+
+    if split_vec[0] == "volume" || split_vec[0] == "v" {
+        return if split_vec.len() < 2 {
+            Action::ChangeVolume {
+                modifier: None,
+                user_id,
+            }
+        } else {
+            info!(
+                "Changing volume to {} (requested by {})",
+                split_vec[1], user_id
+            );
+            let amount = split_vec[1].parse::<u32>();
+            match amount {
+                Err(_) => invalid_usage("volume", user_id),
+                Ok(num) => {
+                    let modifier: f32 = num.min(100) as f32 / 100_f32;
+                    Action::ChangeVolume {
+                        modifier: Some(modifier),
+                        user_id,
+                    }
+                }
+            }
+        };
+    }
+
+    // The remaining commands all need an argument
+    if split_vec.len() < 2 {
+        return invalid_usage(split_vec[0], user_id);
+    }
+
+    if split_vec[0] == "yt" || split_vec[0] == "play" {
+        let (query, options) = split_track_options(&split_vec[1..]);
+        info!("Playing: {} (requested by {})", query, user_id);
+        return Action::PlayAudio(query, options, user_id);
+    }
+
+    if split_vec[0] == "playlocal" {
+        let path = split_vec[1..].join(" ");
+        info!("Playing local file: {} (requested by {})", path, user_id);
+        return Action::PlayLocal(path, user_id);
+    }
+
+    if split_vec[0] == "search" {
+        let query = split_vec[1..].join(" ");
+        info!("Searching: {} (requested by {})", query, user_id);
+        return Action::Search(query, user_id);
+    }
+
+    if split_vec[0] == "name" {
+        let name = split_vec[1..].join(" ");
+        info!("Renaming the bot to {} (requested by {})", name, user_id);
+        return Action::Rename(name, user_id);
+    }
+
+    if split_vec[0] == "pick" {
+        return match split_vec[1].parse::<usize>() {
+            Err(_) => invalid_usage("pick", user_id),
+            Ok(index) => Action::Pick(index, user_id),
+        };
+    }
+
+    if split_vec[0] == "seek" {
+        let arg = split_vec[1];
+        let target = if let Some(secs) = arg.strip_prefix('+') {
+            secs.parse::<u32>().map(SeekPosition::Forward)
+        } else if let Some(secs) = arg.strip_prefix('-') {
+            secs.parse::<u32>().map(SeekPosition::Backward)
+        } else {
+            arg.parse::<u32>().map(SeekPosition::Absolute)
+        };
+        return match target {
+            Err(_) => invalid_usage("seek", user_id),
+            Ok(target) => {
+                info!("Seeking to {:?} (requested by {})", target, user_id);
+                Action::Seek(target, user_id)
+            }
+        };
+    }
+
+    if split_vec[0] == "skipto" {
+        return match split_vec[1].parse::<usize>() {
+            Err(_) => invalid_usage("skipto", user_id),
+            Ok(index) => {
+                info!(
+                    "Skipping to queue entry {} (requested by {})",
+                    index, user_id
+                );
+                Action::SkipTo(index, user_id)
+            }
+        };
+    }
+
+    if split_vec[0] == "remove" || split_vec[0] == "rm" {
+        return match split_vec[1].parse::<usize>() {
+            Err(_) => invalid_usage("remove", user_id),
+            Ok(index) => {
+                info!("Removing queue entry {} (requested by {})", index, user_id);
+                Action::RemoveFromQueue(index, user_id)
+            }
+        };
+    }
+
+    if split_vec[0] == "load" {
+        info!(
+            "Loading playlist {} (requested by {})",
+            split_vec[1], user_id
+        );
+        return Action::LoadPlaylist(split_vec[1].to_string(), user_id);
+    }
+
+    if split_vec[0] == "save" {
+        info!(
+            "Saving playlist {} (requested by {})",
+            split_vec[1], user_id
+        );
+        return Action::SavePlaylist(split_vec[1].to_string(), user_id);
+    }
+
+    if split_vec[0] == "move" {
+        if split_vec.len() < 3 {
+            return invalid_usage("move", user_id);
+        }
+        return match (split_vec[1].parse::<usize>(), split_vec[2].parse::<usize>()) {
+            (Ok(from), Ok(to)) => {
+                info!(
+                    "Moving queue entry {} to {} (requested by {})",
+                    from, to, user_id
+                );
+                Action::MoveQueueItem(from, to, user_id)
+            }
+            _ => invalid_usage("move", user_id),
+        };
+    }
+
+    Action::None
+}
+
+/// Sliding window of the last commands of a user, records `now` if another command fits into it.
+pub fn within_rate_limit(
+    times: &mut VecDeque<Instant>,
+    now: Instant,
+    limit: usize,
+    window: Duration,
+) -> bool {
+    while times
+        .front()
+        .is_some_and(|time| now.duration_since(*time) >= window)
+    {
+        times.pop_front();
+    }
+    if times.len() >= limit {
+        return false;
+    }
+    times.push_back(now);
+    true
+}
+
+/// Actions that interrupt playback for everyone, restricted to `admins` if any are configured.
+fn requires_admin(action: &Action) -> bool {
+    matches!(
+        action,
+        Action::Stop
+            | Action::Skip(_)
+            | Action::SkipTo(..)
+            | Action::Quit
+            | Action::ClearQueue(_)
+            | Action::Restart(_)
+            | Action::Rename(..)
+    )
+}
+
+pub fn is_permitted(action: &Action, uid: Option<&str>, admins: &[String]) -> bool {
+    admins.is_empty() || !requires_admin(action) || is_admin(uid, admins)
+}
+
+/// Unlike `is_permitted`, nobody is an admin if no `admins` are configured.
+pub fn is_admin(uid: Option<&str>, admins: &[String]) -> bool {
+    uid.is_some_and(|uid| admins.iter().any(|admin| admin == uid))
+}
+
+/// Votes needed for `!skip`, never more than there are listeners so a small channel can still skip.
+pub fn required_skip_votes(skip_votes: usize, listeners: Option<usize>) -> usize {
+    listeners
+        .map_or(skip_votes, |listeners| skip_votes.min(listeners))
+        .max(1)
+}
+
+/// Votes needed for `!voteskip`, the share `skip_ratio` of the listeners rounded up.
+pub fn required_vote_ratio(skip_ratio: f32, listeners: Option<usize>) -> usize {
+    let listeners = listeners.unwrap_or(1);
+    ((listeners as f32 * skip_ratio).ceil() as usize).max(1)
+}
+
+/// Listeners that voted to skip the current track, cleared whenever a track starts playing.
+#[derive(Debug, Default)]
+pub struct SkipVotes {
+    voters: Vec<ClientId>,
+}
+
+impl SkipVotes {
+    /// Records the vote of `user_id`, false if they already voted.
+    pub fn vote(&mut self, user_id: ClientId) -> bool {
+        if self.voters.contains(&user_id) {
+            return false;
+        }
+        self.voters.push(user_id);
+        true
+    }
+
+    pub fn count(&self) -> usize {
+        self.voters.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.voters.clear();
+    }
+}
+
+/// Actions only the DJ may use while DJ mode is on, the ones that change playback or the queue.
+/// Everything else, including the replies to rejected commands, stays open to everyone.
+pub fn is_dj_restricted(action: &Action) -> bool {
+    matches!(
+        action,
+        Action::PlayAudio(..)
+            | Action::QueueNextAudio(..)
+            | Action::PlayLocal(..)
+            | Action::Skip(_)
+            | Action::VoteSkip(_)
+            | Action::Pause
+            | Action::Resume
+            | Action::TogglePause(_)
+            | Action::SetMute(..)
+            | Action::Stop
+            | Action::ChangeVolume {
+                modifier: Some(_),
+                ..
+            }
+            | Action::AdjustVolume { .. }
+            | Action::RemoveFromQueue(..)
+            | Action::SkipTo(..)
+            | Action::MoveQueueItem(..)
+            | Action::LoadPlaylist(..)
+            | Action::ClearQueue(_)
+            | Action::EnqueuePlaylist(..)
+            | Action::Pick(..)
+            | Action::SetRepeatMode(Some(_), _)
+            | Action::SetEq(Some(_), _)
+            | Action::SetSpeed(Some(_), _)
+            | Action::Shuffle(_)
+            | Action::SetShuffle(..)
+            | Action::Seek(..)
+            | Action::PlayPrevious(_)
+            | Action::Replay(_)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRIVATE: MessageTarget = MessageTarget::Client(ClientId(1));
+
+    #[test]
+    fn parse_command_table() {
+        let user_id = ClientId(1);
+        let play = |query: &str, volume: Option<f32>| {
+            let options = TrackOptions {
+                volume,
+                ..Default::default()
+            };
+            Action::PlayAudio(query.to_string(), options, user_id)
+        };
+        let volume = |modifier: Option<f32>| Action::ChangeVolume { modifier, user_id };
+        let usage = |command: &str| invalid_usage(command, user_id);
+        let cases = vec![
+            // Not a command
+            ("", Action::None),
+            ("   ", Action::None),
+            ("hello there", Action::None),
+            ("!", Action::None),
+            ("!unknown", Action::None),
+            // Playback
+            ("!play some song", play("some song", None)),
+            ("!yt some song", play("some song", None)),
+            ("!play", usage("play")),
+            (
+                "!play [URL]https://www.youtube.com/watch?v=dQw4w9WgXcQ[/URL]",
+                play("https://www.youtube.com/watch?v=dQw4w9WgXcQ", None),
+            ),
+            ("!play some song vol=150", play("some song", Some(1.0))),
+            (
+                "!next some song",
+                Action::QueueNextAudio("some song".to_string(), TrackOptions::default(), user_id),
+            ),
+            (
+                "!n some song vol=20",
+                Action::QueueNextAudio(
+                    "some song".to_string(),
+                    TrackOptions {
+                        volume: Some(0.2),
+                        ..Default::default()
+                    },
+                    user_id,
+                ),
+            ),
+            (
+                "!playlocal albums/song.mp3",
+                Action::PlayLocal("albums/song.mp3".to_string(), user_id),
+            ),
+            ("!next", Action::Skip(user_id)),
+            ("!n", Action::Skip(user_id)),
+            ("!skip", Action::Skip(user_id)),
+            ("!s", Action::Skip(user_id)),
+            ("!voteskip", Action::VoteSkip(user_id)),
+            ("!stop", Action::Stop),
+            ("!pause", Action::Pause),
+            ("!p", Action::Pause),
+            ("!resume", Action::Resume),
+            ("!r", Action::Resume),
+            ("!continue", Action::Resume),
+            ("!c", Action::Resume),
+            ("!toggle", Action::TogglePause(user_id)),
+            ("!t", Action::TogglePause(user_id)),
+            ("!mute", Action::SetMute(true, user_id)),
+            ("!stfu", Action::SetMute(true, user_id)),
+            ("!unmute", Action::SetMute(false, user_id)),
+            ("!quiet", Action::ToggleQuiet(user_id)),
+            ("!dj", Action::SetDj(true, user_id)),
+            ("!dj off", Action::SetDj(false, user_id)),
+            ("!dj on", usage("dj")),
+            ("!prev", Action::PlayPrevious(user_id)),
+            ("!replay", Action::Replay(user_id)),
+            // Search
+            (
+                "!search lofi beats",
+                Action::Search("lofi beats".to_string(), user_id),
+            ),
+            ("!pick 2", Action::Pick(2, user_id)),
+            ("!pick two", usage("pick")),
+            // Seeking
+            (
+                "!seek 90",
+                Action::Seek(SeekPosition::Absolute(90), user_id),
+            ),
+            (
+                "!seek +10",
+                Action::Seek(SeekPosition::Forward(10), user_id),
+            ),
+            (
+                "!seek -10",
+                Action::Seek(SeekPosition::Backward(10), user_id),
+            ),
+            ("!seek 1:30", usage("seek")),
+            ("!seek", usage("seek")),
+            // Volume
+            ("!volume", volume(None)),
+            ("!v 50", volume(Some(0.5))),
+            ("!v 0", volume(Some(0.0))),
+            ("!volume 250", volume(Some(1.0))),
+            ("!volume -5", usage("volume")),
+            ("!volume loud", usage("volume")),
+            (
+                "!volup",
+                Action::AdjustVolume {
+                    direction: 1,
+                    user_id,
+                },
+            ),
+            (
+                "!vu",
+                Action::AdjustVolume {
+                    direction: 1,
+                    user_id,
+                },
+            ),
+            (
+                "!voldown",
+                Action::AdjustVolume {
+                    direction: -1,
+                    user_id,
+                },
+            ),
+            (
+                "!vd",
+                Action::AdjustVolume {
+                    direction: -1,
+                    user_id,
+                },
+            ),
+            // Playback modes
+            ("!loop", Action::SetRepeatMode(None, user_id)),
+            (
+                "!loop one",
+                Action::SetRepeatMode(Some(RepeatMode::One), user_id),
+            ),
+            ("!loop maybe", usage("loop")),
+            ("!eq", Action::SetEq(None, user_id)),
+            ("!eq bass", Action::SetEq(Some(EqPreset::Bass), user_id)),
+            ("!eq loud", usage("eq")),
+            ("!speed", Action::SetSpeed(None, user_id)),
+            ("!speed 1.5", Action::SetSpeed(Some(1.5), user_id)),
+            ("!speed 2x", Action::SetSpeed(Some(2.0), user_id)),
+            ("!speed fast", usage("speed")),
+            ("!shuffle", Action::Shuffle(user_id)),
+            ("!shuffle on", Action::SetShuffle(true, user_id)),
+            ("!shuffle off", Action::SetShuffle(false, user_id)),
+            ("!shuffle maybe", usage("shuffle")),
+            // Queue
+            ("!queue", Action::ShowQueue(user_id)),
+            ("!ql", Action::ShowQueue(user_id)),
+            ("!history", Action::ShowHistory(user_id)),
+            ("!clear", Action::ClearQueue(user_id)),
+            ("!remove 2", Action::RemoveFromQueue(2, user_id)),
+            ("!rm 3", Action::RemoveFromQueue(3, user_id)),
+            ("!rm last", usage("remove")),
+            ("!skipto 3", Action::SkipTo(3, user_id)),
+            ("!skipto", usage("skipto")),
+            ("!skipto next", usage("skipto")),
+            ("!move 1 3", Action::MoveQueueItem(1, 3, user_id)),
+            ("!move 1", usage("move")),
+            (
+                "!load chill",
+                Action::LoadPlaylist("chill".to_string(), user_id),
+            ),
+            (
+                "!save chill",
+                Action::SavePlaylist("chill".to_string(), user_id),
+            ),
+            // Information and the bot itself
+            ("!info", Action::Info(user_id)),
+            ("!i", Action::Info(user_id)),
+            ("!np", Action::NowPlaying(user_id)),
+            ("!ping", Action::Ping(user_id)),
+            ("!help", Action::Help(user_id)),
+            ("!h", Action::Help(user_id)),
+            ("!join", Action::Join(user_id)),
+            ("!leave", Action::Leave(user_id)),
+            ("!restart", Action::Restart(user_id)),
+            (
+                "!name DJ Bot Friday",
+                Action::Rename("DJ Bot Friday".to_string(), user_id),
+            ),
+            ("!name", usage("name")),
+            ("!quit", Action::Quit),
+            ("!q", Action::Quit),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(
+                parse_command(input, user_id, "!", PRIVATE),
+                expected,
+                "input: {:?}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn url_tags() {
+        let link = "https://www.youtube.com/watch?v=dQw4w9WgXcQ";
+        assert_eq!(strip_url_tags(&format!("[URL]{}[/URL]", link)), link);
+        assert_eq!(strip_url_tags(&format!("[url]{}[/url]", link)), link);
+        assert_eq!(
+            strip_url_tags(&format!("[Url={}]Never Gonna Give You Up[/uRL]", link)),
+            link
+        );
+        assert_eq!(
+            strip_url_tags(&format!("[URL=\"{}\"]title[/URL]", link)),
+            link
+        );
+        assert_eq!(
+            strip_url_tags("[URL=https://x][URL]title[/URL][/URL]"),
+            "https://x"
+        );
+        assert_eq!(strip_url_tags("[URL][URL]a[/URL][/URL] b"), "a b");
+        assert_eq!(strip_url_tags("[urlfoo] [URL]a"), "[urlfoo] a");
+        assert_eq!(strip_url_tags("no tags"), "no tags");
+
+        // Query parameters and percent-encoding survive both tag forms and the argument sanitizing
+        let links = [
+            link,
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ&list=PLx0sYbCqOb8TBPRdmBHs5Iftvv9TPboYG",
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=90s",
+            "https://www.youtube.com/results?search_query=never%20gonna%20give%20you%20up",
+            "https://soundcloud.com/artist/caf%C3%A9-song?in=artist%2Fsets%2Fmix",
+        ];
+        for link in links {
+            assert_eq!(strip_url_tags(&format!("[URL]{}[/URL]", link)), link);
+            assert_eq!(strip_url_tags(&format!("[URL={}]title[/URL]", link)), link);
+            for msg in [
+                format!("!play [URL]{}[/URL]", link),
+                format!("!play [URL={}]Some Title[/URL]", link),
+            ] {
+                assert_eq!(
+                    parse_command(&msg, ClientId(1), "!", PRIVATE),
+                    Action::PlayAudio(link.to_string(), TrackOptions::default(), ClientId(1)),
+                    "message: {:?}",
+                    msg
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn parse_command_custom_prefix() {
+        let user_id = ClientId(1);
+        assert!(matches!(
+            parse_command("~play some song", user_id, "~", PRIVATE),
+            Action::PlayAudio(query, _, _) if query == "some song"
+        ));
+        assert!(matches!(
+            parse_command("~stop", user_id, "~", PRIVATE),
+            Action::Stop
+        ));
+        assert!(matches!(
+            parse_command("!stop", user_id, "~", PRIVATE),
+            Action::None
+        ));
+        assert!(matches!(
+            parse_command("~", user_id, "~", PRIVATE),
+            Action::None
+        ));
+    }
+
+    #[test]
+    fn parse_command_ignores_case() {
+        let user_id = ClientId(1);
+        let parse = |msg: &str| parse_command(msg, user_id, "!", PRIVATE);
+        assert!(matches!(
+            parse("!PLAY Some Song"),
+            Action::PlayAudio(query, _, _) if query == "Some Song"
+        ));
+        assert!(matches!(
+            parse("!Yt https://www.youtube.com/watch?v=AbC"),
+            Action::PlayAudio(query, _, _) if query == "https://www.youtube.com/watch?v=AbC"
+        ));
+        assert!(matches!(
+            parse("!NeXt Song"),
+            Action::QueueNextAudio(query, _, _) if query == "Song"
+        ));
+        assert!(matches!(parse("!N"), Action::Skip(_)));
+        assert!(matches!(parse("!Skip"), Action::Skip(_)));
+        assert!(matches!(parse("!STOP"), Action::Stop));
+        assert!(matches!(parse("!Clear"), Action::ClearQueue(_)));
+        assert!(matches!(parse("!Pause"), Action::Pause));
+        assert!(matches!(parse("!P"), Action::Pause));
+        assert!(matches!(parse("!Resume"), Action::Resume));
+        assert!(matches!(parse("!Continue"), Action::Resume));
+        assert!(matches!(parse("!Toggle"), Action::TogglePause(_)));
+        assert!(matches!(parse("!Prev"), Action::PlayPrevious(_)));
+        assert!(matches!(parse("!HELP"), Action::Help(_)));
+        assert!(matches!(parse("!Info"), Action::Info(_)));
+        assert!(matches!(parse("!Queue"), Action::ShowQueue(_)));
+        assert!(matches!(
+            parse("!Loop all"),
+            Action::SetRepeatMode(Some(RepeatMode::All), _)
+        ));
+        assert!(matches!(parse("!Shuffle"), Action::Shuffle(_)));
+        assert!(matches!(parse("!Quit"), Action::Quit));
+        assert!(matches!(
+            parse("!Volume 50"),
+            Action::ChangeVolume { modifier: Some(modifier), .. } if modifier == 0.5
+        ));
+        assert!(matches!(
+            parse("!SEEK +10"),
+            Action::Seek(SeekPosition::Forward(10), _)
+        ));
+        assert!(matches!(parse("!Remove 2"), Action::RemoveFromQueue(2, _)));
+    }
+
+    #[test]
+    fn admin_only_actions() {
+        let admins = vec!["admin=".to_string()];
+        assert!(is_permitted(&Action::Quit, None, &[]));
+        assert!(is_permitted(&Action::Quit, Some("admin="), &admins));
+        assert!(!is_permitted(&Action::Quit, Some("user="), &admins));
+        assert!(!is_permitted(&Action::Stop, None, &admins));
+        assert!(!is_permitted(
+            &Action::Restart(ClientId(1)),
+            Some("user="),
+            &admins
+        ));
+        assert!(!is_permitted(
+            &Action::Rename("Bot".to_string(), ClientId(1)),
+            Some("user="),
+            &admins
+        ));
+        assert!(is_permitted(
+            &Action::Info(ClientId(1)),
+            Some("user="),
+            &admins
+        ));
+    }
+
+    #[test]
+    fn skip_vote_thresholds() {
+        assert_eq!(required_skip_votes(1, Some(5)), 1);
+        assert_eq!(required_skip_votes(3, Some(5)), 3);
+        assert_eq!(required_skip_votes(3, Some(2)), 2);
+        assert_eq!(required_skip_votes(3, Some(0)), 1);
+        assert_eq!(required_skip_votes(3, None), 3);
+
+        assert_eq!(required_vote_ratio(0.5, Some(3)), 2);
+        assert_eq!(required_vote_ratio(0.5, Some(4)), 2);
+        assert_eq!(required_vote_ratio(1.0, Some(3)), 3);
+        assert_eq!(required_vote_ratio(0.5, Some(0)), 1);
+        assert_eq!(required_vote_ratio(0.5, None), 1);
+    }
+
+    #[test]
+    fn skip_vote_tally() {
+        let mut votes = SkipVotes::default();
+
+        assert!(votes.vote(ClientId(1)));
+        assert!(!votes.vote(ClientId(1)));
+        assert!(votes.vote(ClientId(2)));
+        assert_eq!(votes.count(), 2);
+
+        // A new track starts with no votes, even if it is the same one again
+        votes.clear();
+        assert_eq!(votes.count(), 0);
+        assert!(votes.vote(ClientId(1)));
+    }
+
+    #[test]
+    fn admins_and_dj_mode() {
+        let admins = vec!["admin=".to_string()];
+        assert!(is_admin(Some("admin="), &admins));
+        assert!(!is_admin(Some("user="), &admins));
+        assert!(!is_admin(None, &admins));
+        assert!(!is_admin(Some("user="), &[]));
+
+        assert!(is_dj_restricted(&Action::Skip(ClientId(1))));
+        assert!(is_dj_restricted(&Action::PlayAudio(
+            "some song".to_string(),
+            TrackOptions::default(),
+            ClientId(1)
+        )));
+        assert!(!is_dj_restricted(&Action::ShowQueue(ClientId(1))));
+        assert!(!is_dj_restricted(&Action::SetDj(false, ClientId(1))));
+    }
+
+    #[test]
+    fn dj_restricted_commands() {
+        let user_id = ClientId(2);
+        let cases = [
+            // Change playback or the queue
+            ("!play some song", true),
+            ("!skip", true),
+            ("!pause", true),
+            ("!volume 50", true),
+            ("!clear", true),
+            // Only show information, so a non-DJ gets the real answer
+            ("!np", false),
+            ("!queue", false),
+            ("!volume", false),
+            ("!eq", false),
+            ("!speed", false),
+            ("!loop", false),
+            // Replies to rejected commands, a usage hint and a private-only command
+            ("!play", false),
+            ("!quit", false),
+        ];
+        for (input, restricted) in cases {
+            assert_eq!(
+                is_dj_restricted(&parse_command(input, user_id, "!", MessageTarget::Channel)),
+                restricted,
+                "input: {:?}",
+                input
+            );
+        }
+        assert!(!is_dj_restricted(&Action::RateLimited(user_id)));
+        assert!(!is_dj_restricted(&Action::NotPermitted(user_id)));
+    }
+
+    #[test]
+    fn command_rate_limit() {
+        let window = Duration::from_secs(2);
+        let start = Instant::now();
+        let mut times = VecDeque::new();
+        for _ in 0..3 {
+            assert!(within_rate_limit(&mut times, start, 3, window));
+        }
+        assert!(!within_rate_limit(
+            &mut times,
+            start + Duration::from_secs(1),
+            3,
+            window
+        ));
+        assert!(within_rate_limit(&mut times, start + window, 3, window));
+        assert_eq!(times.len(), 1);
+    }
+
+    #[test]
+    fn private_only_commands() {
+        let user_id = ClientId(1);
+        for target in [MessageTarget::Channel, MessageTarget::Server] {
+            assert_eq!(
+                parse_command("!quit", user_id, "!", target),
+                Action::PrivateOnly(user_id)
+            );
+            assert_eq!(
+                parse_command("!restart", user_id, "!", target),
+                Action::PrivateOnly(user_id)
+            );
+            assert_eq!(
+                parse_command("!queue", user_id, "!", target),
+                Action::ShowQueue(user_id)
+            );
+            assert_eq!(reply_target(target, user_id), target);
+        }
+        assert_eq!(parse_command("!quit", user_id, "!", PRIVATE), Action::Quit);
+        assert_eq!(
+            reply_target(MessageTarget::Client(ClientId(7)), user_id),
+            MessageTarget::Client(user_id)
+        );
+    }
+
+    #[test]
+    fn usage_replies() {
+        let user_id = ClientId(1);
+        assert_eq!(
+            parse_command("!volume abc", user_id, "!", PRIVATE),
+            Action::Usage("!volume <0-100>".to_string(), user_id)
+        );
+        assert_eq!(
+            parse_command("!v abc", user_id, "!", PRIVATE),
+            parse_command("!volume abc", user_id, "!", PRIVATE)
+        );
+        assert!(matches!(
+            parse_command("!seek xyz", user_id, "!", PRIVATE),
+            Action::Usage(usage, _) if usage.starts_with("!seek <seconds>")
+        ));
+        // Unknown commands stay silent
+        assert_eq!(
+            parse_command("!frobnicate 3", user_id, "!", PRIVATE),
+            Action::None
+        );
+        assert_eq!(
+            parse_command("!unknown", user_id, "!", PRIVATE),
+            Action::None
+        );
+    }
+
+    #[test]
+    fn parse_command_track_volume() {
+        let user_id = ClientId(1);
+        assert!(matches!(
+            parse_command("!play some song vol=50", user_id, "!", PRIVATE),
+            Action::PlayAudio(query, TrackOptions { volume: Some(volume), .. }, _)
+                if query == "some song" && volume == 0.5
+        ));
+        assert!(matches!(
+            parse_command("!next https://example.com/song.mp3 vol=20", user_id, "!", PRIVATE),
+            Action::QueueNextAudio(query, TrackOptions { volume: Some(_), .. }, _)
+                if query == "https://example.com/song.mp3"
+        ));
+        assert!(matches!(
+            parse_command("!play vol=50", user_id, "!", PRIVATE),
+            Action::PlayAudio(query, TrackOptions { volume: None, .. }, _) if query == "vol=50"
+        ));
+    }
+
+    #[test]
+    fn parse_command_track_clip() {
+        let user_id = ClientId(1);
+        assert_eq!(
+            parse_command(
+                "!play some song start=30 vol=40 end=90",
+                user_id,
+                "!",
+                PRIVATE
+            ),
+            Action::PlayAudio(
+                "some song".to_string(),
+                TrackOptions {
+                    volume: Some(0.4),
+                    start: Some(30),
+                    end: Some(90),
+                },
+                user_id,
+            )
+        );
+        assert_eq!(
+            parse_command("!next some song end=20", user_id, "!", PRIVATE),
+            Action::QueueNextAudio(
+                "some song".to_string(),
+                TrackOptions {
+                    end: Some(20),
+                    ..Default::default()
+                },
+                user_id,
+            )
+        );
+        // Only trailing options count, a lone option is the search term
+        assert_eq!(
+            parse_command("!play start=10", user_id, "!", PRIVATE),
+            Action::PlayAudio("start=10".to_string(), TrackOptions::default(), user_id)
+        );
+        assert_eq!(
+            parse_command("!play start=x some song", user_id, "!", PRIVATE),
+            Action::PlayAudio(
+                "start=x some song".to_string(),
+                TrackOptions::default(),
+                user_id
+            )
+        );
+    }
+
+    #[test]
+    fn clip_validation() {
+        let clip = |start: Option<u32>, end: Option<u32>| TrackOptions {
+            start,
+            end,
+            ..Default::default()
+        };
+        assert!(validate_clip(&TrackOptions::default(), None).is_ok());
+        assert!(validate_clip(&clip(Some(30), Some(90)), Some(120)).is_ok());
+        assert!(validate_clip(&clip(None, Some(500)), None).is_ok());
+        assert!(validate_clip(&clip(Some(90), Some(30)), None).is_err());
+        assert!(validate_clip(&clip(Some(30), Some(30)), None).is_err());
+        assert!(validate_clip(&clip(None, Some(0)), None).is_err());
+        assert!(validate_clip(&clip(Some(120), None), Some(120)).is_err());
+    }
+}
@@ -0,0 +1,259 @@
+use crate::{Config, QueueState, DEFAULT_CONFIG_FILE, PLAYLIST_DIR};
+use anyhow::{bail, Context, Result};
+use log::{error, info, warn};
+use serde_json::{Map, Value};
+use std::fs::File;
+use std::io::{BufReader, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+/// Prefix of the environment variables that override config fields, e.g. `TSBOT_HTTP_PORT`.
+const ENV_PREFIX: &str = "TSBOT_";
+/// Config fields that stay text even if their environment variable looks like a number, e.g. a
+/// numeric password.
+const STRING_FIELDS: [&str; 14] = [
+    "host",
+    "password",
+    "name",
+    "id",
+    "http_host",
+    "http_message",
+    "http_token",
+    "prefix",
+    "idle_message",
+    "music_dir",
+    "audio_bitrate",
+    "buffer_size",
+    "cookies_file",
+    "cookies_from_browser",
+];
+
+/// Environment variables take precedence over the file. Without a path given on the command line
+/// the default file may be missing if they supply every required field.
+pub fn read_config(config_file_path: Option<&str>) -> Result<Config> {
+    let explicit = config_file_path.is_some();
+    let config_file_path = config_file_path.unwrap_or(DEFAULT_CONFIG_FILE);
+    let mut fields = match File::open(config_file_path) {
+        Ok(config_file) => match serde_json::from_reader(BufReader::new(config_file)) {
+            Ok(Value::Object(fields)) => fields,
+            Ok(_) => bail!("Failed to parse config: expected a JSON object"),
+            Err(why) => bail!("Failed to parse config: {}", why),
+        },
+        Err(why) if why.kind() == ErrorKind::NotFound && !explicit => {
+            info!(
+                "{} not found, reading the config from {}* environment variables",
+                config_file_path, ENV_PREFIX
+            );
+            Map::new()
+        }
+        Err(why) => bail!(
+            "Unable to open configuration file {}: {}",
+            config_file_path,
+            why
+        ),
+    };
+
+    let vars = env::vars_os()
+        .filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?)));
+    apply_env_overrides(&mut fields, vars);
+
+    serde_json::from_value(Value::Object(fields)).context("Failed to parse config")
+}
+
+/// `TSBOT_HTTP_PORT=3000` sets `http_port`. Values are parsed as JSON so numbers, booleans and
+/// lists work, anything else and every value of a `STRING_FIELDS` field is taken as string.
+fn apply_env_overrides(
+    fields: &mut Map<String, Value>,
+    vars: impl IntoIterator<Item = (String, String)>,
+) {
+    for (key, raw) in vars {
+        let field = match key.strip_prefix(ENV_PREFIX) {
+            Some(field) if !field.is_empty() => field.to_lowercase(),
+            _ => continue,
+        };
+        let value = match serde_json::from_str::<Value>(&raw) {
+            // Quoted like `TSBOT_PASSWORD='"1234"'`
+            Ok(Value::String(value)) => Value::String(value),
+            _ if STRING_FIELDS.contains(&field.as_str()) => Value::String(raw),
+            Ok(value) => value,
+            Err(_) => Value::String(raw),
+        };
+        fields.insert(field, value);
+    }
+}
+
+pub fn read_queue_state(path: &str) -> QueueState {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            if e.kind() != ErrorKind::NotFound {
+                warn!(
+                    "Unable to open {}, starting with an empty queue: {}",
+                    path, e
+                );
+            }
+            return QueueState::default();
+        }
+    };
+
+    match serde_json::from_reader(BufReader::new(file)) {
+        Ok(state) => state,
+        Err(e) => {
+            warn!(
+                "Failed to parse {}, starting with an empty queue: {}",
+                path, e
+            );
+            QueueState::default()
+        }
+    }
+}
+
+pub fn write_queue_state(path: &str, state: &QueueState) {
+    let file = match File::create(path) {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Unable to write {}: {}", path, e);
+            return;
+        }
+    };
+
+    if let Err(e) = serde_json::to_writer(file, state) {
+        error!("Failed to serialize queue state: {}", e);
+    }
+}
+
+/// Playlist names may only contain letters, digits, `-` and `_` so they can't escape `PLAYLIST_DIR`.
+fn playlist_path(name: &str) -> Result<PathBuf> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!("invalid name, use only letters, digits, - and _");
+    }
+    Ok(Path::new(PLAYLIST_DIR).join(format!("{}.txt", name)))
+}
+
+pub fn load_playlist(name: &str) -> Result<Vec<String>> {
+    let path = playlist_path(name)?;
+    let content =
+        fs::read_to_string(&path).with_context(|| format!("unable to read {}", path.display()))?;
+
+    Ok(content
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| line.to_string())
+        .collect())
+}
+
+pub fn save_playlist(name: &str, links: &[&str]) -> Result<()> {
+    let path = playlist_path(name)?;
+    fs::create_dir_all(PLAYLIST_DIR)
+        .with_context(|| format!("unable to create {}", PLAYLIST_DIR))?;
+    let mut content = links.join("\n");
+    content.push('\n');
+    fs::write(&path, content).with_context(|| format!("unable to write {}", path.display()))
+}
+
+/// A number optionally followed by one of `units`, like `48K` or `16M`.
+fn is_number_with_unit(s: &str, units: &[char]) -> bool {
+    let digits = s.strip_suffix(|c: char| units.contains(&c)).unwrap_or(s);
+    !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Bitrates yt-dlp accepts for `--audio-quality`, e.g. `48K`, or a VBR quality from `0` to `10`.
+pub fn is_valid_bitrate(bitrate: &str) -> bool {
+    is_number_with_unit(bitrate, &['K', 'k'])
+}
+
+/// Sizes yt-dlp accepts for `--buffer-size`, e.g. `1024`, `512K` or `16M`.
+pub fn is_valid_buffer_size(size: &str) -> bool {
+    is_number_with_unit(size, &['K', 'k', 'M', 'm'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audio_quality_settings() {
+        assert!(is_valid_bitrate("48K"));
+        assert!(is_valid_bitrate("128k"));
+        assert!(is_valid_bitrate("5"));
+        assert!(!is_valid_bitrate("48M"));
+        assert!(!is_valid_bitrate("K"));
+        assert!(!is_valid_bitrate("48K --exec rm"));
+        assert!(is_valid_buffer_size("16M"));
+        assert!(is_valid_buffer_size("1024"));
+        assert!(!is_valid_buffer_size(""));
+        assert!(!is_valid_buffer_size("16MB"));
+    }
+
+    #[test]
+    fn env_overrides() {
+        let mut fields = match serde_json::json!({"host": "file.example", "password": "old"}) {
+            Value::Object(fields) => fields,
+            _ => unreachable!(),
+        };
+        let vars = [
+            ("TSBOT_HOST", "env.example"),
+            ("TSBOT_PASSWORD", "1234"),
+            ("TSBOT_HTTP_PORT", "8080"),
+            ("TSBOT_FOLLOW", "true"),
+            ("TSBOT_ADMINS", r#"["a=", "b="]"#),
+            ("TSBOT_", "ignored"),
+            ("HOME", "/root"),
+        ];
+        apply_env_overrides(
+            &mut fields,
+            vars.iter().map(|(k, v)| (k.to_string(), v.to_string())),
+        );
+
+        assert_eq!(fields["host"], "env.example");
+        assert_eq!(fields["password"], "1234");
+        assert_eq!(fields["http_port"], 8080);
+        assert_eq!(fields["follow"], true);
+        assert_eq!(fields["admins"], serde_json::json!(["a=", "b="]));
+        assert_eq!(fields.len(), 5);
+    }
+
+    #[test]
+    fn env_only_strings() {
+        let mut fields = Map::new();
+        let vars = [
+            ("TSBOT_PASSWORD", "1234"),
+            ("TSBOT_NAME", "123"),
+            ("TSBOT_ID", "true"),
+            ("TSBOT_HTTP_TOKEN", r#""quoted""#),
+            ("TSBOT_HTTP_PORT", "8080"),
+        ];
+        apply_env_overrides(
+            &mut fields,
+            vars.iter().map(|(k, v)| (k.to_string(), v.to_string())),
+        );
+
+        assert_eq!(fields["password"], "1234");
+        assert_eq!(fields["name"], "123");
+        assert_eq!(fields["id"], "true");
+        assert_eq!(fields["http_token"], "quoted");
+        assert_eq!(fields["http_port"], 8080);
+    }
+
+    #[test]
+    fn explicit_config_path() {
+        let error = read_config(Some("does-not-exist.json")).unwrap_err();
+        assert!(format!("{:#}", error).contains("does-not-exist.json"));
+    }
+
+    #[test]
+    fn playlist_names() {
+        assert_eq!(
+            playlist_path("chill_mix-2").unwrap(),
+            Path::new(PLAYLIST_DIR).join("chill_mix-2.txt")
+        );
+        assert!(playlist_path("../config").is_err());
+        assert!(playlist_path("a/b").is_err());
+        assert!(playlist_path("").is_err());
+    }
+}
@@ -0,0 +1,168 @@
+use crate::helper::is_url;
+use crate::HistoryEntry;
+use chrono::DateTime;
+use std::collections::VecDeque;
+
+/// Lists the `count` most recent tracks with the (UTC) time they finished, newest first.
+pub fn format_history(history: &VecDeque<HistoryEntry>, count: usize) -> String {
+    if history.is_empty() {
+        return "No tracks played yet".to_string();
+    }
+
+    let mut msg = "\nRecently played:\n".to_string();
+    for (i, played) in history.iter().take(count).enumerate() {
+        let time = DateTime::parse_from_rfc3339(&played.finished_at)
+            .map(|time| time.format("%H:%M").to_string())
+            .unwrap_or_else(|_| "--:--".to_string());
+        let title = played.entry.title.as_deref().unwrap_or(&played.entry.link);
+        msg.push_str(&format!(
+            "{}. [{}] {} (requested by {})\n",
+            i + 1,
+            time,
+            title,
+            played.entry.requester
+        ));
+    }
+    msg
+}
+
+pub fn format_duration(secs: u32) -> String {
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+/// Cuts titles down so `!info` and `!np` stay well within the length TeamSpeak allows for messages.
+fn shorten_title(title: &str) -> String {
+    const MAX_TITLE_LEN: usize = 80;
+
+    if title.chars().count() > MAX_TITLE_LEN {
+        let short: String = title.chars().take(MAX_TITLE_LEN - 1).collect();
+        format!("{}…", short)
+    } else {
+        title.to_string()
+    }
+}
+
+/// Makes web links clickable, local files and raw BBCode clients get the plain link.
+pub fn format_link(link: &str, bbcode: bool) -> String {
+    if bbcode && is_url(link) {
+        format!("[URL]{}[/URL]", link)
+    } else {
+        link.to_string()
+    }
+}
+
+pub fn format_track_info(title: &str, artist: &str, link: &str, bbcode: bool) -> String {
+    let title = shorten_title(title);
+    if bbcode {
+        format!(
+            "[b]{}[/b]\nChannel: [color=gray]{}[/color]\nLink: {}",
+            title,
+            artist,
+            format_link(link, bbcode)
+        )
+    } else {
+        format!("Title: {}\nChannel: {}\nLink: {}", title, artist, link)
+    }
+}
+
+pub fn format_now_playing(title: &str, progress: &str, bbcode: bool) -> String {
+    let title = shorten_title(title);
+    if bbcode {
+        format!("[b]{}[/b] {}", title, progress)
+    } else {
+        format!("{} {}", title, progress)
+    }
+}
+
+/// Renders e.g. `[####----] 1:23 / 3:45`, prefixed with ⏸ while paused.
+pub fn progress_bar(position: u32, duration: u32, paused: bool) -> String {
+    const BAR_WIDTH: u32 = 20;
+
+    let filled = if duration > 0 {
+        (position.min(duration) * BAR_WIDTH / duration) as usize
+    } else {
+        0
+    };
+    let bar = format!(
+        "[{}{}]",
+        "#".repeat(filled),
+        "-".repeat(BAR_WIDTH as usize - filled)
+    );
+    let total = if duration > 0 {
+        format_duration(duration)
+    } else {
+        "?".to_string()
+    };
+    let marker = if paused { "⏸ " } else { "" };
+
+    format!(
+        "{}{} {} / {}",
+        marker,
+        bar,
+        format_duration(position),
+        total
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helper::{entry, push_history};
+    use crate::QueueEntry;
+
+    #[test]
+    fn track_cards() {
+        let link = "https://example.com/song";
+        assert_eq!(
+            format_track_info("Song", "Artist", link, true),
+            "[b]Song[/b]\nChannel: [color=gray]Artist[/color]\nLink: [URL]https://example.com/song[/URL]"
+        );
+        assert_eq!(
+            format_track_info("Song", "Artist", link, false),
+            "Title: Song\nChannel: Artist\nLink: https://example.com/song"
+        );
+        assert_eq!(format_link("local:song.mp3", true), "local:song.mp3");
+        assert_eq!(format_now_playing("Song", "0:01", true), "[b]Song[/b] 0:01");
+        assert_eq!(format_now_playing("Song", "0:01", false), "Song 0:01");
+
+        let card = format_now_playing(&"x".repeat(500), "0:01", false);
+        assert_eq!(card.chars().count(), 80 + " 0:01".len());
+        assert!(card.contains('…'));
+    }
+
+    #[test]
+    fn progress_bar_rendering() {
+        assert_eq!(
+            progress_bar(83, 225, false),
+            "[#######-------------] 1:23 / 3:45"
+        );
+        assert_eq!(
+            progress_bar(0, 0, true),
+            "⏸ [--------------------] 0:00 / ?"
+        );
+    }
+
+    #[test]
+    fn history_listing() {
+        let mut history = VecDeque::new();
+        assert_eq!(format_history(&history, 10), "No tracks played yet");
+
+        let entry = entry("https://example.com/song.mp3");
+        push_history(&mut history, entry.clone(), 2);
+        push_history(
+            &mut history,
+            QueueEntry {
+                title: Some("Some Song".to_string()),
+                ..entry.clone()
+            },
+            2,
+        );
+        push_history(&mut history, entry, 2);
+        assert_eq!(history.len(), 2);
+
+        let listing = format_history(&history, 10);
+        assert!(listing.contains("1. ["));
+        assert!(listing.contains("] https://example.com/song.mp3 (requested by alice)"));
+        assert!(listing.contains("] Some Song (requested by alice)"));
+    }
+}
@@ -1,243 +1,298 @@
-use crate::{Action, Config, InfoJson, PlaybackState};
-use anyhow::{Context, Result};
-use axum::extract::State;
-use axum::Json;
+use crate::ts::client_name;
+use crate::{HistoryEntry, QueueEntry, SearchCheck, HTTP_CLIENT_ID, LOCAL_PREFIX};
+use anyhow::{bail, Context, Result};
 use chrono::Utc;
-use log::{error, info};
 use serde_json::json;
-use std::fs;
-use std::fs::File;
-use std::io::BufReader;
-use std::sync::Arc;
-use tokio::net::lookup_host;
-use tokio::sync::Mutex;
-use tsclientlib::{ClientId, Connection, Identity, MessageTarget, OutCommandExt};
+use std::collections::VecDeque;
+use std::env;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use tsclientlib::{ClientId, Connection};
 use which::which;
 
-pub fn check_dependencies() -> () {
-    if which("ffmpeg").is_err() {
-        panic!("Unable to find ffmpeg");
-    };
+/// Logs human readable text by default, or one JSON object per line with `LOG_FORMAT=json`.
+pub fn init_logger() {
+    let mut builder = env_logger::Builder::from_default_env();
+
+    let json = env::var("LOG_FORMAT")
+        .map(|v| v.eq_ignore_ascii_case("json"))
+        .unwrap_or(false);
+    if json {
+        builder.format(|buf, record| {
+            writeln!(
+                buf,
+                "{}",
+                json!({
+                    "timestamp": Utc::now().to_rfc3339(),
+                    "level": record.level().to_string(),
+                    "module": record.module_path(),
+                    "message": record.args().to_string(),
+                })
+            )
+        });
+    }
+    builder.init();
+}
 
-    if which("yt-dlp").is_err() {
-        panic!("Unable to find yt-dlp");
-    };
+pub fn check_dependencies() -> Result<()> {
+    let missing: Vec<&str> = ["ffmpeg", "yt-dlp"]
+        .iter()
+        .copied()
+        .filter(|program| which(program).is_err())
+        .collect();
+    if !missing.is_empty() {
+        bail!("Unable to find {}", missing.join(" and "));
+    }
+    Ok(())
 }
 
-pub fn read_config(config_file_path: &str) -> Config {
-    let config_file = match File::open(config_file_path) {
-        Ok(id) => id,
-        Err(why) => {
-            panic!("Unable to open configuration file: {}", why);
-        }
-    };
+/// Resolves `relative` inside `music_dir`, refusing absolute paths, `..` and symlinks out of it.
+pub fn local_file_path(music_dir: &str, relative: &str) -> Result<PathBuf> {
+    let relative_path = Path::new(relative);
+    if relative.is_empty()
+        || !relative_path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        bail!("invalid path, use a path relative to the music directory");
+    }
 
-    match serde_json::from_reader(config_file) {
-        Ok(cfg) => cfg,
-        Err(why) => {
-            panic!("Failed to parse config: {}", why);
-        }
+    let music_dir = Path::new(music_dir)
+        .canonicalize()
+        .with_context(|| format!("unable to open {}", music_dir))?;
+    let path = music_dir
+        .join(relative_path)
+        .canonicalize()
+        .with_context(|| format!("{} does not exist", relative))?;
+    if !path.starts_with(&music_dir) || !path.is_file() {
+        bail!("{} is not a file in the music directory", relative);
     }
+    Ok(path)
 }
 
-pub fn connect_to_ts(config: Config) -> Connection {
-    let con_config = Connection::build(config.host)
-        .name(config.name)
-        .password(config.password)
-        .log_commands(false)
-        .log_packets(false)
-        .log_udp_packets(false);
+/// Title shown for a local file, its name without the extension.
+pub fn local_title(relative: &str) -> String {
+    Path::new(relative)
+        .file_stem()
+        .map_or(relative.to_string(), |stem| {
+            stem.to_string_lossy().to_string()
+        })
+}
 
-    let id = match Identity::new_from_str(&config.id) {
-        Ok(id) => id,
-        Err(why) => {
-            panic!("Invalid teamspeak3 identity string: {}", why);
-        }
+pub fn queue_entry(con: &Connection, link: String, user_id: ClientId) -> QueueEntry {
+    let requester = if user_id == HTTP_CLIENT_ID {
+        "HTTP API".to_string()
+    } else {
+        client_name(con, user_id).unwrap_or_else(|| user_id.to_string())
     };
 
-    let con_config = con_config.identity(id);
-
-    match con_config.connect() {
-        Ok(con) => con,
-        Err(why) => {
-            panic!("Unable to connect: {}", why);
-        }
+    QueueEntry {
+        link,
+        requester,
+        requester_id: Some(user_id),
+        volume: None,
+        title: None,
+        start: None,
+        end: None,
     }
 }
 
-pub fn read_info_json() -> Result<InfoJson> {
-    let file = File::open("-.info.json").with_context(|| "Failed to open the file: -.info.json")?;
+/// Queue entry of `link` requested by alice, for tests.
+#[cfg(test)]
+pub fn entry(link: &str) -> QueueEntry {
+    QueueEntry {
+        link: link.to_string(),
+        requester: "alice".to_string(),
+        requester_id: Some(ClientId(1)),
+        volume: None,
+        title: None,
+        start: None,
+        end: None,
+    }
+}
 
-    let reader = BufReader::new(file);
+/// Records a finished track, the oldest ones are dropped beyond `max_len`.
+pub fn push_history(history: &mut VecDeque<HistoryEntry>, entry: QueueEntry, max_len: usize) {
+    history.push_front(HistoryEntry {
+        entry,
+        finished_at: Utc::now().to_rfc3339(),
+    });
+    history.truncate(max_len);
+}
 
-    let info_json: InfoJson = serde_json::from_reader(reader)
-        .with_context(|| "Failed to parse the JSON file: -.info.json")?;
+/// Whether the link is playing or waiting in the queue already.
+pub fn is_queued(link: &str, current: Option<&QueueEntry>, queue: &VecDeque<QueueEntry>) -> bool {
+    let link = link.trim();
+    current
+        .into_iter()
+        .chain(queue.iter())
+        .any(|entry| entry.link.trim() == link)
+}
 
-    Ok(info_json)
+/// How many more tracks fit into the queue, regardless of who requested them.
+pub fn queue_space_left(queue: &VecDeque<QueueEntry>, max_queue_size: Option<usize>) -> usize {
+    max_queue_size.map_or(usize::MAX, |max| max.saturating_sub(queue.len()))
 }
 
-pub async fn cleanup_process(process: &mut std::process::Child, name: &str) -> () {
-    if let Err(e) = process.kill() {
-        error!("Failed to kill {}: {}", name, e);
-    }
-    match process.wait() {
-        Ok(status) => {
-            if !status.success() && !status.code().is_none() {
-                error!("{} exited with non-zero status: {:?}", name, status.code());
-            }
+/// How many more tracks the user may queue, requests from the HTTP API are not limited.
+pub fn queue_slots_left(
+    queue: &VecDeque<QueueEntry>,
+    user_id: ClientId,
+    limit: Option<usize>,
+) -> usize {
+    match limit {
+        Some(limit) if user_id != HTTP_CLIENT_ID => {
+            let queued = queue
+                .iter()
+                .filter(|entry| entry.requester_id == Some(user_id))
+                .count();
+            limit.saturating_sub(queued)
         }
-        Err(e) => error!("Failed to wait on {}: {}", name, e),
+        _ => usize::MAX,
     }
 }
 
-pub fn send_ts_message(con: &mut Connection, target: MessageTarget, msg: &str) -> () {
-    let state = con.get_state().unwrap_or_else(|e| {
-        panic!("Unable to get state: {}", e);
-    });
-
-    if let Err(e) = state.send_message(target, &msg).send_with_result(con) {
-        error!("Message sending error: {}", e);
-    }
+pub fn is_url(s: &str) -> bool {
+    s.starts_with("http://") || s.starts_with("https://")
 }
 
-fn sanitize(s: &str) -> String {
-    s.chars()
-        .filter(|c| {
-            c.is_alphanumeric()
-                || [
-                    ' ', '.', ' ', '=', '\t', ',', '?', '!', ':', '&', '/', '-', '_',
-                ]
-                .contains(c)
-        })
-        .collect()
+/// Links and local files are always played, search terms only if they are worth spawning
+/// yt-dlp for.
+pub fn is_fetchable(link: &str, check: SearchCheck) -> bool {
+    link.starts_with(LOCAL_PREFIX) || is_url(link) || is_plausible_query(link, check)
 }
 
-pub fn parse_command(msg: &str, user_id: ClientId) -> Action {
-    let stripped = msg.replace("[URL]", "").replace("[/URL]", "");
-    let sanitized = sanitize(&stripped).trim().to_string();
-
-    if &sanitized[..=0] != "!" {
-        return Action::None;
+fn is_plausible_query(query: &str, check: SearchCheck) -> bool {
+    let mut words = query.split_whitespace();
+    match check {
+        SearchCheck::Off => true,
+        SearchCheck::Lenient => words.any(looks_like_word),
+        SearchCheck::Strict => {
+            let words: Vec<&str> = words.collect();
+            !words.is_empty() && words.iter().all(|word| looks_like_word(word))
+        }
     }
+}
 
-    let split_vec: Vec<&str> = sanitized.split(' ').collect();
+/// Keyboard mashing like `asdfghjkl` lacks vowels or has long runs of consonants. Short words
+/// are always accepted for numbers and acronyms like `BTS`, other scripts aren't checked at all.
+fn looks_like_word(word: &str) -> bool {
+    const MAX_CONSONANT_RUN: usize = 5;
 
-    if split_vec[0] == "!stop" {
-        info!("Stopping all tracks (requested by {})", user_id);
-        return Action::Stop;
+    let chars: Vec<char> = word.chars().filter(|c| c.is_alphanumeric()).collect();
+    if chars.iter().any(|c| c.is_alphabetic() && !c.is_ascii()) {
+        return true;
     }
-
-    if split_vec[0] == "!pause" || split_vec[0] == "!p" {
-        return Action::Pause;
+    let letters: Vec<char> = chars
+        .iter()
+        .filter(|c| c.is_ascii_alphabetic())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    if letters.len() <= 4 {
+        return !chars.is_empty();
     }
+    let is_vowel = |c: &char| "aeiouy".contains(*c);
+    let longest_run = letters.split(is_vowel).map(|run| run.len()).max();
+    letters.iter().any(is_vowel) && longest_run.map_or(true, |run| run <= MAX_CONSONANT_RUN)
+}
 
-    if split_vec[0] == "!continue"
-        || split_vec[0] == "!c"
-        || split_vec[0] == "!resume"
-        || split_vec[0] == "!r"
-    {
-        return Action::Resume;
-    }
+/// Links straight to an audio file, they are played as is and never treated as a playlist.
+pub fn is_audio_file(link: &str) -> bool {
+    const AUDIO_EXTENSIONS: [&str; 7] = ["mp3", "ogg", "opus", "flac", "wav", "m4a", "aac"];
 
-    if split_vec[0] == "!next" || split_vec[0] == "!n" {
-        if split_vec.len() > 1 {
-            info!("Queueing: {} (requested by {})", split_vec[1], user_id);
-            return Action::QueueNextAudio(split_vec[1].to_string(), user_id);
-        }
-        return Action::Skip;
-    }
+    let path = link.split(|c| c == '?' || c == '#').next().unwrap_or(link);
+    is_url(link)
+        && path
+            .rsplit_once('.')
+            .is_some_and(|(_, ext)| AUDIO_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
 
-    if split_vec[0] == "!skip" || split_vec[0] == "!s" {
-        return Action::Skip;
-    }
+pub fn is_playlist(link: &str) -> bool {
+    is_url(link)
+        && !is_audio_file(link)
+        && (link.contains("list=") || link.contains("/playlist") || link.contains("/sets/"))
+}
 
-    if split_vec[0] == "!help" || split_vec[0] == "!h" {
-        return Action::Help(user_id);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InfoJson, MetadataCache};
+
+    #[test]
+    fn search_queries() {
+        assert!(is_plausible_query(
+            "never gonna give you up",
+            SearchCheck::Lenient
+        ));
+        assert!(is_plausible_query("BTS", SearchCheck::Lenient));
+        assert!(is_plausible_query("1999", SearchCheck::Lenient));
+        assert!(is_plausible_query("strengths", SearchCheck::Lenient));
+        assert!(is_plausible_query("千本桜", SearchCheck::Lenient));
+        assert!(!is_plausible_query("asdfghjkl", SearchCheck::Lenient));
+        assert!(!is_plausible_query("qwrtzpsd", SearchCheck::Lenient));
+        assert!(!is_plausible_query("?!", SearchCheck::Lenient));
+        assert!(is_plausible_query("lofi asdfghjkl", SearchCheck::Lenient));
+        assert!(!is_plausible_query("lofi asdfghjkl", SearchCheck::Strict));
+        assert!(is_plausible_query("asdfghjkl", SearchCheck::Off));
     }
 
-    if split_vec[0] == "!info" || split_vec[0] == "!i" {
-        return Action::Info(user_id);
+    #[test]
+    fn audio_file_links() {
+        assert!(is_audio_file("https://example.com/song.MP3"));
+        assert!(is_audio_file("https://example.com/song.ogg?list=1"));
+        assert!(!is_playlist("https://example.com/song.ogg?list=1"));
+        assert!(!is_audio_file(
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ"
+        ));
+        assert!(!is_audio_file("song.mp3"));
     }
 
-    if split_vec[0] == "!quit" || split_vec[0] == "!q" {
-        info!("Quitting (requested by {})", user_id);
-        return Action::Quit;
+    #[test]
+    fn local_file_paths() {
+        let music_dir = env::temp_dir().display().to_string();
+        assert!(local_file_path(&music_dir, "../etc/passwd").is_err());
+        assert!(local_file_path(&music_dir, "albums/../../secret.mp3").is_err());
+        assert!(local_file_path(&music_dir, "/etc/passwd").is_err());
+        assert!(local_file_path(&music_dir, "").is_err());
+        assert!(local_file_path(&music_dir, "does-not-exist.mp3").is_err());
+        assert_eq!(
+            local_title("albums/Some Artist - Song.flac"),
+            "Some Artist - Song"
+        );
     }
 
-    if split_vec[0] == "!volume" || split_vec[0] == "!v" {
-        return if split_vec.len() < 2 {
-            Action::ChangeVolume {
-                modifier: -1.0,
-                user_id,
-            }
-        } else {
-            info!(
-                "Changing volume to {} (requested by {})",
-                split_vec[1], user_id
-            );
-            let amount = split_vec[1].parse::<u32>();
-            match amount {
-                Err(_) => Action::None,
-                Ok(num) => {
-                    let modifier: f32 = num.max(0).min(100) as f32 / 100_f32;
-                    Action::ChangeVolume { modifier, user_id }
-                }
-            }
+    #[test]
+    fn metadata_cache_eviction() {
+        let info_json = |title: &str| InfoJson {
+            id: title.to_string(),
+            title: title.to_string(),
+            channel: None,
+            uploader: None,
+            duration: 0,
+            view_count: None,
+            webpage_url: String::new(),
+            is_live: None,
         };
-    }
-
-    // return if no second argument
-    if split_vec.len() < 2 {
-        return Action::None;
-    }
-
-    if split_vec[0] == "!yt" || split_vec[0] == "!play" {
-        info!("Playing: {} (requested by {})", split_vec[1], user_id);
-        return Action::PlayAudio(split_vec[1].to_string(), user_id);
-    }
-
-    Action::None
-}
-
-pub async fn get_status(State(state): State<Arc<Mutex<PlaybackState>>>) -> Json<serde_json::Value> {
-    let playback_state = state.lock().await;
-    let mut duration: u32 = 0;
+        let mut cache = MetadataCache::default();
+        for i in 0..=crate::MAX_METADATA_CACHE {
+            cache.insert(i.to_string(), info_json(&i.to_string()));
+        }
+        assert!(cache.get("0").is_none());
+        assert_eq!(cache.title("1"), Some("1"));
+        assert_eq!(cache.entries.len(), crate::MAX_METADATA_CACHE);
 
-    if fs::metadata("-.info.json").is_ok() && playback_state.link.clone().unwrap_or_default() != ""
-    {
-        duration = match read_info_json() {
-            Ok(info_json) => info_json.duration,
-            Err(err) => {
-                error!("Failed to read info JSON: {}", err);
-                0
-            }
-        };
+        cache.insert("1".to_string(), info_json("updated"));
+        assert_eq!(cache.title("1"), Some("updated"));
+        assert_eq!(cache.order.len(), crate::MAX_METADATA_CACHE);
     }
 
-    Json(json!({
-        "time": playback_state.time_passed,
-        "timestamp": Utc::now().to_rfc3339(),
-        "paused": playback_state.paused,
-        "duration": duration,
-        "link": playback_state.link.clone().unwrap_or_default(),
-    }))
-}
-
-pub async fn resolve_host(host: &str) -> Result<String> {
-    match lookup_host((host, 0)).await {
-        Ok(addresses) => {
-            if let Some(addr) = addresses.filter(|addr| addr.ip().is_ipv4()).next() {
-                info!("Resolved host: {} to IP: {}", host, addr.ip());
-                return Ok(addr.ip().to_string());
-            }
-        }
-        Err(e) => {
-            error!(
-                "DNS resolution error for host: {}. Reason: {}. Using hostname as fallback.",
-                host, e
-            );
-        }
+    #[test]
+    fn queue_space() {
+        let entry = entry("https://example.com/song.mp3");
+        let queue: VecDeque<QueueEntry> = vec![entry.clone(), entry].into();
+        assert_eq!(queue_space_left(&queue, None), usize::MAX);
+        assert_eq!(queue_space_left(&queue, Some(5)), 3);
+        assert_eq!(queue_space_left(&queue, Some(2)), 0);
+        assert_eq!(queue_space_left(&queue, Some(1)), 0);
     }
-
-    Ok(host.to_string())
 }
@@ -0,0 +1,321 @@
+use crate::pipeline::volume_to_gain;
+use crate::ytdlp::read_info_json;
+use crate::{
+    Action, ConnectionStatus, ControlRequest, MetadataCache, Metrics, PlaybackState, QueueEntry,
+    TrackOptions, HTTP_CLIENT_ID,
+};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::Utc;
+use log::{error, info};
+use serde_json::json;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::Instant;
+
+/// Everything the status JSON is built from, the queue is the snapshot the main loop keeps updated.
+#[derive(Clone)]
+pub struct StatusSources {
+    pub playback: Arc<Mutex<PlaybackState>>,
+    pub queue: Arc<Mutex<VecDeque<QueueEntry>>>,
+    pub metadata_cache: Arc<Mutex<MetadataCache>>,
+}
+
+pub async fn get_status(State(sources): State<StatusSources>) -> Json<serde_json::Value> {
+    // The locks are taken one after another so the main loop is never blocked on two of them
+    let (queue_length, next_entry) = {
+        let queue = sources.queue.lock().await;
+        (queue.len(), queue.front().cloned())
+    };
+    let next = match next_entry {
+        Some(entry) => {
+            let cache = sources.metadata_cache.lock().await;
+            json!({
+                "link": entry.link,
+                "requester": entry.requester,
+                "title": cache.get(&entry.link).map(|info_json| &info_json.title),
+            })
+        }
+        None => serde_json::Value::Null,
+    };
+    let playback_state = sources.playback.lock().await;
+
+    // Only the info json of the track that is playing right now is trusted, yt-dlp may not
+    // have written it yet
+    let duration = match (&playback_state.link, &playback_state.info_path) {
+        (Some(_), Some(info_path)) if Path::new(info_path).is_file() => {
+            match read_info_json(info_path) {
+                Ok(info_json) => info_json.duration,
+                Err(err) => {
+                    error!("Failed to read info JSON: {}", err);
+                    0
+                }
+            }
+        }
+        _ => 0,
+    };
+
+    Json(json!({
+        "time": playback_state.time_passed,
+        "timestamp": Utc::now().to_rfc3339(),
+        "paused": playback_state.paused,
+        "muted": playback_state.muted,
+        "duration": duration,
+        "link": playback_state.link.clone().unwrap_or_default(),
+        "repeat": playback_state.repeat.as_str(),
+        "eq": playback_state.eq.as_str(),
+        "speed": playback_state.speed,
+        "volume": (playback_state.volume * 100.0).round(),
+        "gain": volume_to_gain(playback_state.volume),
+        "queue_length": queue_length,
+        "next": next,
+    }))
+}
+
+/// Identifies the bot for status pages, `message` is the configured `http_message`.
+pub async fn get_root(name: String, message: String, started: Instant) -> Json<serde_json::Value> {
+    Json(json!({
+        "message": message,
+        "name": name,
+        "version": env!("CARGO_PKG_VERSION"),
+        "uptime_secs": started.elapsed().as_secs(),
+    }))
+}
+
+/// Responds with 503 while the TeamSpeak connection is down so orchestrators can restart the bot.
+pub async fn get_health(
+    status: Arc<Mutex<ConnectionStatus>>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let status = status.lock().await;
+    let code = if status.connected {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        code,
+        Json(json!({
+            "connected": status.connected,
+            "last_disconnect": status.last_disconnect,
+        })),
+    )
+}
+
+/// Renders the metrics in the Prometheus text exposition format.
+pub async fn get_metrics(
+    metrics: Arc<Metrics>,
+    state: Arc<Mutex<PlaybackState>>,
+    queue: Arc<Mutex<VecDeque<QueueEntry>>>,
+) -> impl IntoResponse {
+    let volume = state.lock().await.volume;
+    let queue_length = queue.lock().await.len();
+
+    let mut body = String::new();
+    let mut push = |name: &str, kind: &str, help: &str, value: String| {
+        body.push_str(&format!(
+            "# HELP tsmusicbot_{name} {help}\n# TYPE tsmusicbot_{name} {kind}\ntsmusicbot_{name} {value}\n",
+            name = name,
+            kind = kind,
+            help = help,
+            value = value
+        ));
+    };
+    push(
+        "tracks_played_total",
+        "counter",
+        "Tracks that started playing.",
+        metrics.tracks_played.load(Ordering::Relaxed).to_string(),
+    );
+    push(
+        "skips_total",
+        "counter",
+        "Tracks skipped by users.",
+        metrics.skips.load(Ordering::Relaxed).to_string(),
+    );
+    push(
+        "errors_total",
+        "counter",
+        "Tracks that failed to play.",
+        metrics.errors.load(Ordering::Relaxed).to_string(),
+    );
+    push(
+        "queue_length",
+        "gauge",
+        "Tracks waiting in the queue.",
+        queue_length.to_string(),
+    );
+    push(
+        "volume",
+        "gauge",
+        "Current volume from 0 to 1.",
+        volume.to_string(),
+    );
+
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+pub async fn ws_status(ws: WebSocketUpgrade, sources: StatusSources) -> Response {
+    ws.on_upgrade(move |socket| forward_status(socket, sources))
+}
+
+/// Sends the current status right away and again after every change of the playback state.
+async fn forward_status(mut socket: WebSocket, sources: StatusSources) {
+    let mut events = sources.playback.lock().await.events.subscribe();
+
+    loop {
+        let status = get_status(State(sources.clone())).await;
+        if socket
+            .send(Message::Text(status.to_string()))
+            .await
+            .is_err()
+        {
+            break;
+        }
+
+        match events.recv().await {
+            Ok(()) | Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+/// Rejects requests without a matching `Authorization: Bearer` header while `http_token` is set.
+pub async fn require_token(
+    State(token): State<Option<String>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match token {
+        Some(token) if !is_authorized(request.headers(), &token) => {
+            (StatusCode::UNAUTHORIZED, "Missing or invalid bearer token").into_response()
+        }
+        _ => next.run(request).await,
+    }
+}
+
+fn is_authorized(headers: &HeaderMap, token: &str) -> bool {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| constant_time_eq(given.trim().as_bytes(), token.as_bytes()))
+}
+
+/// Compares every byte regardless of where the first mismatch is, so the time a request takes
+/// does not reveal how much of the token it guessed right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Titles and durations are null until the metadata of a link has been fetched.
+pub async fn get_queue(
+    State(queue): State<Arc<Mutex<VecDeque<QueueEntry>>>>,
+    metadata_cache: Arc<Mutex<MetadataCache>>,
+) -> Json<serde_json::Value> {
+    let queue = queue.lock().await;
+    let cache = metadata_cache.lock().await;
+
+    Json(json!(queue
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            let info_json = cache.get(&entry.link);
+            json!({
+                "position": i + 1,
+                "link": entry.link,
+                "requester": entry.requester,
+                "title": info_json.map(|info_json| &info_json.title),
+                "duration": info_json.map(|info_json| info_json.duration),
+            })
+        })
+        .collect::<Vec<_>>()))
+}
+
+pub async fn control(
+    Json(request): Json<ControlRequest>,
+    status_send: mpsc::Sender<Action>,
+    sources: StatusSources,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let action = match request.action.as_str() {
+        "pause" => Action::Pause,
+        "resume" => Action::Resume,
+        "skip" => Action::Skip(HTTP_CLIENT_ID),
+        "stop" => Action::Stop,
+        "volume" => match request.value.as_ref().and_then(|v| v.as_u64()) {
+            Some(num) => Action::ChangeVolume {
+                modifier: Some(num.min(100) as f32 / 100_f32),
+                user_id: HTTP_CLIENT_ID,
+            },
+            None => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    "volume requires a numeric value from 0 to 100".to_string(),
+                ))
+            }
+        },
+        "play" => match request.value.as_ref().and_then(|v| v.as_str()) {
+            Some(link) => {
+                Action::PlayAudio(link.to_string(), TrackOptions::default(), HTTP_CLIENT_ID)
+            }
+            None => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    "play requires a link or search term as value".to_string(),
+                ))
+            }
+        },
+        other => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Unknown action: {}", other),
+            ))
+        }
+    };
+
+    info!("Control request: {:?}", action);
+    if let Err(e) = status_send.send(action).await {
+        error!("Status packet sending error: {}", e);
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+    }
+
+    Ok(get_status(State(sources)).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn bearer_tokens() {
+        let mut headers = HeaderMap::new();
+        assert!(!is_authorized(&headers, "secret"));
+
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer secret"),
+        );
+        assert!(is_authorized(&headers, "secret"));
+        assert!(!is_authorized(&headers, "other"));
+        assert!(!is_authorized(&headers, "secre"));
+        assert!(!is_authorized(&headers, "secrets"));
+
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Basic secret"),
+        );
+        assert!(!is_authorized(&headers, "secret"));
+
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer "));
+        assert!(!is_authorized(&headers, "secret"));
+    }
+}
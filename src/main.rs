@@ -2,63 +2,529 @@ extern crate audiopus;
 extern crate byteorder;
 extern crate serde;
 extern crate serde_json;
+mod commands;
+mod config;
+mod format;
 mod helper;
+mod http;
+mod pipeline;
+mod ts;
+mod ytdlp;
 
-use anyhow::{bail, Result};
-use axum::extract::State;
-use axum::{routing::get, Router};
-use byteorder::{BigEndian, ReadBytesExt};
+use anyhow::{bail, Context, Result};
+use axum::extract::{State, WebSocketUpgrade};
+use axum::{
+    middleware,
+    routing::{get, post},
+    Router,
+};
+use byteorder::{BigEndian, ByteOrder};
+use chrono::Utc;
 use futures::prelude::*;
 use log::{debug, error, info, warn};
-use serde::Deserialize;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Deserializer, Serialize};
 use socketioxide::{extract::SocketRef, SocketIo};
-use std::collections::VecDeque;
-use std::io::ErrorKind;
-use std::process::{Command, Stdio};
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
 use std::{env, fs};
-use tokio::signal::unix::SignalKind;
-use tokio::sync::{mpsc, Mutex};
-use tokio::time::{sleep, timeout, Duration};
+use tokio::io::AsyncReadExt;
+use tokio::process::{Child, ChildStderr, ChildStdout, Command};
+use tokio::sync::{broadcast, mpsc, Mutex, Notify};
+use tokio::time::{interval, sleep, sleep_until, timeout, Duration, Instant, MissedTickBehavior};
 
+use crate::commands::{
+    is_admin, is_dj_restricted, is_permitted, parse_command, reply_target, required_skip_votes,
+    required_vote_ratio, validate_clip, within_rate_limit, SkipVotes,
+};
+use crate::config::{
+    is_valid_bitrate, is_valid_buffer_size, load_playlist, read_config, read_queue_state,
+    save_playlist, write_queue_state,
+};
+use crate::format::{
+    format_duration, format_history, format_link, format_now_playing, format_track_info,
+    progress_bar,
+};
 use crate::helper::{
-    check_dependencies, cleanup_process, connect_to_ts, get_status, parse_command, read_config,
-    read_info_json, resolve_host, send_ts_message,
+    check_dependencies, init_logger, is_fetchable, is_playlist, is_queued, is_url, local_file_path,
+    local_title, push_history, queue_entry, queue_slots_left, queue_space_left,
+};
+use crate::http::{
+    control, get_health, get_metrics, get_queue, get_root, get_status, require_token, ws_status,
+    StatusSources,
+};
+use crate::pipeline::{atempo_filter, cleanup_process, read_frame, volume_to_gain};
+use crate::ts::{
+    announcement, check_name, client_name, client_uid, connect_to_ts, connection_ping,
+    count_listeners, follow_client, lookup_ip, move_to_channel, ping_message, resolve_host,
+    send_confirmation, send_ts_message, set_ts_name, split_hostname, wait_for_connection,
+    PendingAnnouncement,
+};
+use crate::ytdlp::{
+    fetch_metadata, fetch_playlist_entries, is_socket_timeout, is_transient_ytdlp_error,
+    read_info_json, search, ytdlp_error_message,
+};
+use tsclientlib::events::{Event, PropertyId};
+use tsclientlib::{
+    ChannelId, ClientId, Connection, DisconnectOptions, Identity, MessageTarget, StreamItem,
 };
-use tsclientlib::events::Event;
-use tsclientlib::{ClientId, Connection, DisconnectOptions, MessageTarget, StreamItem};
 use tsproto_packets::packets::{AudioData, CodecType, OutAudio, OutPacket};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 struct Config {
     host: String,
     password: String,
     name: String,
     id: String,
+    /// Resolve `host` to an IP address before connecting instead of leaving it to tsclientlib.
+    #[serde(default)]
+    resolve_host: bool,
+    /// Address family `resolve_host` and `--check` look for.
+    #[serde(default)]
+    ip_version: IpVersion,
+    #[serde(default = "default_http_host")]
+    http_host: String,
+    #[serde(default = "default_http_port")]
+    http_port: u16,
+    #[serde(default = "default_http_message")]
+    http_message: String,
+    /// Bearer token every route except `/health` requires, including socket.io, all are open while unset.
+    #[serde(default)]
+    http_token: Option<String>,
+    #[serde(default = "default_fade_in_ms")]
+    fade_in_ms: u32,
+    /// Duration of the fade-out when a track is skipped or stopped.
+    #[serde(default = "default_fade_out_ms")]
+    fade_out_ms: u32,
+    #[serde(default)]
+    crossfade_secs: u32,
+    #[serde(default)]
+    normalize: bool,
+    /// Downmix to a single channel before encoding, halving the bandwidth.
+    #[serde(default)]
+    mono: bool,
+    /// Encode with `Application::LowDelay` instead of `Application::Audio`.
+    #[serde(default)]
+    low_delay: bool,
+    #[serde(default)]
+    opus_complexity: Option<u8>,
+    /// Bits per second of the opus stream sent to TeamSpeak, chosen by opus if unset.
+    #[serde(default)]
+    opus_bitrate: Option<i32>,
+    #[serde(default)]
+    prefetch: bool,
+    #[serde(default = "default_prefix")]
+    prefix: String,
+    #[serde(default)]
+    admins: Vec<String>,
+    #[serde(default)]
+    follow: bool,
+    /// Leave out the confirmations of routine commands, errors and requested information are still sent.
+    #[serde(default)]
+    quiet: bool,
+    /// Announce every track once its audio starts, and the end of the queue in the channel.
+    #[serde(default)]
+    announce: bool,
+    /// Name the requester of a track in its announcement.
+    #[serde(default)]
+    announce_requester: bool,
+    /// Format `!info` and `!np` with BBCode, turned off for clients that show it raw.
+    #[serde(default = "default_bbcode")]
+    bbcode: bool,
+    #[serde(default)]
+    auto_pause: bool,
+    #[serde(default)]
+    idle_timeout_secs: Option<u64>,
+    #[serde(default)]
+    idle_action: IdleAction,
+    #[serde(default)]
+    search_check: SearchCheck,
+    /// Sent to the channel when the bot goes idle.
+    #[serde(default)]
+    idle_message: Option<String>,
+    /// Directory `!playlocal` plays files from.
+    #[serde(default)]
+    music_dir: Option<String>,
+    /// Channel id `!leave` moves the bot to.
+    #[serde(default)]
+    idle_channel: Option<u64>,
+    #[serde(default)]
+    max_queue_per_user: Option<usize>,
+    /// Maximum number of tracks waiting in the queue, from everyone together.
+    #[serde(default)]
+    max_queue_size: Option<usize>,
+    #[serde(default)]
+    dedupe: bool,
+    /// Distinct listeners that have to `!skip` a track before it is skipped, `1` skips instantly.
+    #[serde(default = "default_skip_votes")]
+    skip_votes: usize,
+    /// Share of the listeners that has to `!voteskip` a track before it is skipped.
+    #[serde(default = "default_skip_ratio")]
+    skip_ratio: f32,
+    #[serde(default = "default_volume_step")]
+    volume_step: u32,
+    /// Commands a user may send within `rate_limit_secs`, `0` disables the limit.
+    #[serde(default = "default_rate_limit_commands")]
+    rate_limit_commands: usize,
+    #[serde(default = "default_rate_limit_secs")]
+    rate_limit_secs: u64,
+    #[serde(default = "default_yt_dlp_retries")]
+    yt_dlp_retries: u32,
+    /// Passed to yt-dlp as `--socket-timeout`.
+    #[serde(default = "default_socket_timeout_secs")]
+    socket_timeout_secs: u32,
+    /// Passed to yt-dlp as `--audio-quality`.
+    #[serde(default = "default_audio_bitrate")]
+    audio_bitrate: String,
+    /// Passed to yt-dlp as `--buffer-size`.
+    #[serde(default = "default_buffer_size")]
+    buffer_size: String,
+    /// Netscape cookies file passed to yt-dlp as `--cookies`, `cookies.txt` is used if it exists.
+    #[serde(default)]
+    cookies_file: Option<String>,
+    /// Browser (and optionally profile) passed to yt-dlp as `--cookies-from-browser`.
+    #[serde(default)]
+    cookies_from_browser: Option<String>,
+    /// Livestreams are cut off after this many seconds, without it they are refused.
+    #[serde(default)]
+    max_live_secs: Option<u32>,
 }
 
-#[derive(Debug, Deserialize)]
+fn default_http_host() -> String {
+    "0.0.0.0".to_string()
+}
+
+fn default_http_port() -> u16 {
+    3000
+}
+
+fn default_http_message() -> String {
+    "TSMusicbot is running!".to_string()
+}
+
+fn default_rate_limit_commands() -> usize {
+    3
+}
+
+fn default_rate_limit_secs() -> u64 {
+    2
+}
+
+fn default_bbcode() -> bool {
+    true
+}
+
+fn default_volume_step() -> u32 {
+    10
+}
+
+fn default_audio_bitrate() -> String {
+    "48K".to_string()
+}
+
+fn default_buffer_size() -> String {
+    "16M".to_string()
+}
+
+fn default_socket_timeout_secs() -> u32 {
+    5
+}
+
+fn default_yt_dlp_retries() -> u32 {
+    2
+}
+
+fn default_prefix() -> String {
+    "!".to_string()
+}
+
+fn default_fade_in_ms() -> u32 {
+    500
+}
+
+fn default_fade_out_ms() -> u32 {
+    200
+}
+
+fn default_skip_votes() -> usize {
+    1
+}
+
+fn default_skip_ratio() -> f32 {
+    0.5
+}
+
+impl Config {
+    /// Checks every field up front and reports all problems at once.
+    fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+
+        if self.host.trim().is_empty() {
+            problems.push("host must not be empty".to_string());
+        }
+        if self.name.trim().is_empty() {
+            problems.push("name must not be empty".to_string());
+        }
+        if let Err(e) = Identity::new_from_str(&self.id) {
+            problems.push(format!("id is not a valid teamspeak3 identity: {}", e));
+        }
+        if self.http_port == 0 {
+            problems.push("http_port must be between 1 and 65535".to_string());
+        }
+        if matches!(&self.http_token, Some(token) if token.trim().is_empty()) {
+            problems.push("http_token must not be empty".to_string());
+        }
+        if self.prefix.is_empty() {
+            problems.push("prefix must not be empty".to_string());
+        }
+        if !is_valid_bitrate(&self.audio_bitrate) {
+            problems.push(format!(
+                "audio_bitrate must be a bitrate like 48K or a quality from 0 to 10, got {:?}",
+                self.audio_bitrate
+            ));
+        }
+        if !is_valid_buffer_size(&self.buffer_size) {
+            problems.push(format!(
+                "buffer_size must be a size like 16M, got {:?}",
+                self.buffer_size
+            ));
+        }
+        if let Some(cookies_file) = &self.cookies_file {
+            if !Path::new(cookies_file).is_file() {
+                problems.push(format!("cookies_file {} does not exist", cookies_file));
+            }
+            if self.cookies_from_browser.is_some() {
+                problems.push("cookies_file and cookies_from_browser are exclusive".to_string());
+            }
+        }
+        if self.cookies_from_browser.as_deref().map(str::trim) == Some("") {
+            problems.push("cookies_from_browser must not be empty".to_string());
+        }
+        if self
+            .opus_complexity
+            .is_some_and(|complexity| complexity > 10)
+        {
+            problems.push("opus_complexity must be between 0 and 10".to_string());
+        }
+        if let Some(bitrate) = self.opus_bitrate {
+            if !(500..=512_000).contains(&bitrate) {
+                problems.push("opus_bitrate must be between 500 and 512000".to_string());
+            }
+        }
+        if self.rate_limit_commands > 0 && self.rate_limit_secs == 0 {
+            problems.push("rate_limit_secs must be at least 1".to_string());
+        }
+        if let Some(music_dir) = &self.music_dir {
+            if !Path::new(music_dir).is_dir() {
+                problems.push(format!("music_dir {} is not a directory", music_dir));
+            }
+        }
+        if self.socket_timeout_secs == 0 {
+            problems.push("socket_timeout_secs must be at least 1".to_string());
+        }
+        if self.max_live_secs == Some(0) {
+            problems.push("max_live_secs must be at least 1".to_string());
+        }
+        if self.max_queue_per_user == Some(0) {
+            problems.push("max_queue_per_user must be at least 1".to_string());
+        }
+        if self.max_queue_size == Some(0) {
+            problems.push("max_queue_size must be at least 1".to_string());
+        }
+        if self.skip_votes == 0 {
+            problems.push("skip_votes must be at least 1".to_string());
+        }
+        if !(self.skip_ratio > 0.0 && self.skip_ratio <= 1.0) {
+            problems.push(format!(
+                "skip_ratio must be greater than 0 and at most 1, got {}",
+                self.skip_ratio
+            ));
+        }
+
+        if !problems.is_empty() {
+            bail!("Invalid configuration:\n  - {}", problems.join("\n  - "));
+        }
+        Ok(())
+    }
+}
+
+/// Only `title` is guaranteed, other extractors than YouTube (SoundCloud, direct files) leave
+/// out or null the remaining fields.
+#[derive(Debug, PartialEq, Deserialize)]
 struct InfoJson {
     id: String,
     title: String,
-    channel: String,
+    #[serde(default)]
+    channel: Option<String>,
+    #[serde(default)]
+    uploader: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
     duration: u32,
-    view_count: u64,
+    #[serde(default)]
+    view_count: Option<u64>,
+    #[serde(default)]
     webpage_url: String,
+    #[serde(default)]
+    is_live: Option<bool>,
 }
 
-#[derive(Debug)]
+impl InfoJson {
+    fn is_live(&self) -> bool {
+        self.is_live.unwrap_or(false)
+    }
+
+    fn artist(&self) -> &str {
+        self.channel
+            .as_deref()
+            .or(self.uploader.as_deref())
+            .unwrap_or("Unknown")
+    }
+}
+
+/// Durations are fractional seconds for some extractors and null for live streams.
+fn deserialize_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    Ok(Option::<f64>::deserialize(deserializer)?.map_or(0, |secs| secs.round() as u32))
+}
+
+#[derive(Debug, Deserialize)]
+struct ControlRequest {
+    action: String,
+    value: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct QueueEntry {
+    link: String,
+    requester: String,
+    /// Client ids are reassigned on every connect, so they are not persisted.
+    #[serde(skip)]
+    requester_id: Option<ClientId>,
+    /// Overrides the global volume while this track plays.
+    #[serde(default)]
+    volume: Option<f32>,
+    /// Filled in from the info json once the track started playing.
+    #[serde(default)]
+    title: Option<String>,
+    /// Only the part between these positions (in seconds) is played.
+    #[serde(default)]
+    start: Option<u32>,
+    #[serde(default)]
+    end: Option<u32>,
+}
+
+impl QueueEntry {
+    fn with_options(self, options: TrackOptions) -> QueueEntry {
+        QueueEntry {
+            volume: options.volume,
+            start: options.start,
+            end: options.end,
+            ..self
+        }
+    }
+}
+
+/// Settings given with `!play` or `!next` that only apply to that track.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct TrackOptions {
+    volume: Option<f32>,
+    start: Option<u32>,
+    end: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct HistoryEntry {
+    entry: QueueEntry,
+    /// RFC 3339 timestamp of when the track stopped playing.
+    finished_at: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct QueueState {
+    current: Option<QueueEntry>,
+    position: f64,
+    queue: VecDeque<QueueEntry>,
+    #[serde(default)]
+    history: VecDeque<HistoryEntry>,
+}
+
+#[derive(Debug, PartialEq)]
 enum Action {
-    PlayAudio(String, ClientId),
-    QueueNextAudio(String, ClientId),
-    Skip,
+    /// Link or search term, the options for just this track and the requester.
+    PlayAudio(String, TrackOptions, ClientId),
+    QueueNextAudio(String, TrackOptions, ClientId),
+    /// Path of a file relative to `music_dir`.
+    PlayLocal(String, ClientId),
+    /// Skips right away or counts as vote, see `Config::skip_votes`.
+    Skip(ClientId),
+    /// Always counts as vote, see `Config::skip_ratio`.
+    VoteSkip(ClientId),
     Pause,
     Resume,
+    TogglePause(ClientId),
+    /// Keep decoding but stop sending audio (true) or send it again (false).
+    SetMute(bool, ClientId),
+    /// Turn quiet mode on or off, see `Config::quiet`.
+    ToggleQuiet(ClientId),
+    /// New nickname of the bot, kept across reconnects.
+    Rename(String, ClientId),
     Stop,
-    ChangeVolume { modifier: f32, user_id: ClientId },
+    /// Without a modifier the current volume is reported.
+    ChangeVolume {
+        modifier: Option<f32>,
+        user_id: ClientId,
+    },
+    /// Moves the volume `volume_step` percent up (1) or down (-1).
+    AdjustVolume {
+        direction: i32,
+        user_id: ClientId,
+    },
     Info(ClientId),
+    NowPlaying(ClientId),
+    Ping(ClientId),
+    ShowQueue(ClientId),
+    ShowHistory(ClientId),
+    RemoveFromQueue(usize, ClientId),
+    /// Skips the current track and the queued tracks before this position.
+    SkipTo(usize, ClientId),
+    MoveQueueItem(usize, usize, ClientId),
+    LoadPlaylist(String, ClientId),
+    SavePlaylist(String, ClientId),
+    ClearQueue(ClientId),
+    EnqueuePlaylist(Vec<String>, ClientId),
+    Search(String, ClientId),
+    SearchResults(Vec<InfoJson>, ClientId),
+    Pick(usize, ClientId),
+    SetRepeatMode(Option<RepeatMode>, ClientId),
+    /// Without a preset the current one is reported.
+    SetEq(Option<EqPreset>, ClientId),
+    /// Without a factor the current speed is reported.
+    SetSpeed(Option<f32>, ClientId),
+    Shuffle(ClientId),
+    SetShuffle(bool, ClientId),
+    Seek(SeekPosition, ClientId),
+    PlayPrevious(ClientId),
+    Replay(ClientId),
+    Join(ClientId),
+    Leave(ClientId),
     Help(ClientId),
+    ListenersChanged,
+    NotPermitted(ClientId),
+    /// The command is only accepted in a private message to the bot.
+    PrivateOnly(ClientId),
+    /// A known command with malformed arguments, carries its syntax.
+    Usage(String, ClientId),
+    /// Claim (true) or give up (false) exclusive control of playback.
+    SetDj(bool, ClientId),
+    /// Someone else is the DJ right now.
+    NotDj(ClientId),
+    /// The user sent more than `rate_limit_commands` commands within `rate_limit_secs`.
+    RateLimited(ClientId),
+    /// Reconnect to the server without losing the queue or volume.
+    Restart(ClientId),
     Quit,
     None,
 }
@@ -67,182 +533,838 @@ enum Action {
 enum PlayTaskCmd {
     Pause,
     Resume,
-    Stop,
-    ChangeVolume { modifier: f32 },
+    /// Ends the track, after fading it out unless the bot is shutting down.
+    Stop {
+        fade_out: bool,
+    },
+    ChangeVolume {
+        modifier: f32,
+    },
+    Seek {
+        position: u32,
+    },
+    /// Start the pipeline of the next track, mixed in when crossfading, otherwise prefetched.
+    PrepareNext {
+        link: String,
+        start_secs: u32,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+enum SeekPosition {
+    Absolute(u32),
+    Forward(u32),
+    Backward(u32),
 }
 
 #[derive(Debug)]
 enum AudioPacket {
     Payload(OutPacket),
+    /// The current track reached the crossfade or prefetch window and wants the next link.
+    RequestNext,
+    /// The current track finished with the next track's pipeline already running, which played
+    /// for the given amount of seconds while crossfading (zero if it was only prefetched).
+    Handoff(Pipeline, u32),
+    /// The title of the current track, sent once yt-dlp wrote its info json.
+    NowPlaying(String),
+    /// yt-dlp failed to fetch the track, the message goes to the requester.
+    Error(ClientId, String),
     None,
 }
 
+/// What the bot does after `idle_timeout_secs` without playing anything.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum IdleAction {
+    #[default]
+    Disconnect,
+    /// Move to `idle_channel` and stay connected.
+    Leave,
+}
+
+/// Address family a hostname is resolved to with `resolve_host`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum IpVersion {
+    #[default]
+    Ipv4,
+    Ipv6,
+    /// Whichever address the system resolver lists first.
+    Auto,
+}
+
+impl IpVersion {
+    fn as_str(&self) -> &'static str {
+        match self {
+            IpVersion::Ipv4 => "IPv4",
+            IpVersion::Ipv6 => "IPv6",
+            IpVersion::Auto => "IP",
+        }
+    }
+}
+
+/// How search terms of `!play` and `!next` are checked before yt-dlp is spawned for them.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SearchCheck {
+    Off,
+    /// At least one word has to look like a real word.
+    #[default]
+    Lenient,
+    /// Every word has to look like a real word.
+    Strict,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RepeatMode {
+    Off,
+    One,
+    All,
+}
+
+/// ffmpeg filter presets selected with `!eq`, applied when a pipeline is spawned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EqPreset {
+    Flat,
+    Bass,
+    Treble,
+    Vocal,
+}
+
+impl EqPreset {
+    fn as_str(&self) -> &'static str {
+        match self {
+            EqPreset::Flat => "flat",
+            EqPreset::Bass => "bass",
+            EqPreset::Treble => "treble",
+            EqPreset::Vocal => "vocal",
+        }
+    }
+
+    fn filter(&self) -> Option<&'static str> {
+        match self {
+            EqPreset::Flat => None,
+            EqPreset::Bass => Some("bass=g=8:f=110:w=0.6"),
+            EqPreset::Treble => Some("treble=g=6:f=3000"),
+            EqPreset::Vocal => Some("highpass=f=120,equalizer=f=2500:t=q:w=1.5:g=4"),
+        }
+    }
+}
+
+impl RepeatMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RepeatMode::Off => "off",
+            RepeatMode::One => "one",
+            RepeatMode::All => "all",
+        }
+    }
+}
+
 #[derive(Clone)]
 struct PlaybackState {
     time_passed: f64,
     paused: bool,
+    muted: bool,
     link: Option<String>,
+    info_path: Option<String>,
+    repeat: RepeatMode,
+    volume: f32,
+    eq: EqPreset,
+    speed: f32,
+    /// Notifies `/ws` subscribers, call `changed` after modifying anything but `time_passed`.
+    events: broadcast::Sender<()>,
 }
 
-const DEFAULT_VOLUME: f32 = 0.2;
+impl PlaybackState {
+    /// Equalizer preset and speed a new pipeline is spawned with.
+    fn filters(&self) -> (EqPreset, f32) {
+        (self.eq, self.speed)
+    }
 
-async fn play_file(
-    link: String,
-    pkt_send: mpsc::Sender<AudioPacket>,
-    mut cmd_recv: mpsc::Receiver<PlayTaskCmd>,
-    volume: f32,
-    playback_state: Arc<Mutex<PlaybackState>>,
-) {
-    const FRAME_SIZE: usize = 960;
-    const MAX_PACKET_SIZE: usize = 3 * 1276;
+    fn changed(&self) {
+        // Sending only fails while nobody is subscribed
+        let _ = self.events.send(());
+    }
+}
 
-    let codec = CodecType::OpusMusic;
-    let mut current_volume = volume;
-    let mut paused = false;
-    let mut time_passed: f64 = 0.0;
+/// Metadata of queued links fetched ahead of playback, the oldest entries are evicted first.
+#[derive(Debug, Default)]
+struct MetadataCache {
+    entries: HashMap<String, InfoJson>,
+    order: VecDeque<String>,
+}
 
-    let mut state = playback_state.lock().await;
-    state.time_passed = time_passed;
-    state.paused = paused;
-    state.link = Some(link.clone());
-    drop(state);
+impl MetadataCache {
+    fn get(&self, link: &str) -> Option<&InfoJson> {
+        self.entries.get(link)
+    }
+
+    fn title(&self, link: &str) -> Option<&str> {
+        self.get(link).map(|info_json| info_json.title.as_str())
+    }
+
+    /// Livestreams and links without a known length report a duration of 0.
+    fn duration(&self, link: &str) -> Option<u32> {
+        self.get(link)
+            .map(|info_json| info_json.duration)
+            .filter(|duration| *duration > 0)
+    }
+
+    fn insert(&mut self, link: String, info_json: InfoJson) {
+        if self.entries.insert(link.clone(), info_json).is_none() {
+            self.order.push_back(link);
+        }
+        while self.order.len() > MAX_METADATA_CACHE {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// User facing volume from 0 to 1, mapped to a gain by `volume_to_gain`.
+const DEFAULT_VOLUME: f32 = 0.5;
+const DEFAULT_CONFIG_FILE: &str = "config.json";
+const SEARCH_RESULTS: usize = 5;
+const MAX_HISTORY: usize = 50;
+const MAX_METADATA_CACHE: usize = 500;
+/// Pause between two metadata lookups so a long playlist doesn't start dozens of yt-dlp at once.
+const METADATA_FETCH_DELAY: Duration = Duration::from_secs(1);
+/// Tracks listed by `!history`.
+const HISTORY_LINES: usize = 10;
+const QUEUE_STATE_FILE: &str = "queue_state.json";
+const PLAYLIST_DIR: &str = "playlists";
+/// Links of files from `music_dir`, followed by the path relative to it.
+const LOCAL_PREFIX: &str = "local:";
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+const MAX_RECONNECT_ATTEMPTS: u32 = 20;
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long before the end of a track the next one is spawned when `prefetch` is enabled.
+const PREFETCH_SECS: u32 = 10;
+const MIN_SPEED: f32 = 0.5;
+const MAX_SPEED: f32 = 2.0;
+const AUTO_PAUSE_DELAY: Duration = Duration::from_secs(5);
+/// Tracks are announced once they played this long, so skipping through the queue only
+/// announces the track it stopped at.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5);
+/// Delay before the first retry of a failed yt-dlp, doubled for every further attempt.
+const YTDLP_RETRY_DELAY: Duration = Duration::from_secs(2);
+/// A timed out yt-dlp is retried once with its socket timeout multiplied by this.
+const SOCKET_TIMEOUT_RETRY_FACTOR: u32 = 3;
+/// Requester used for actions coming from the HTTP API, replies to it are dropped.
+const HTTP_CLIENT_ID: ClientId = ClientId(0);
+/// Reply to search terms rejected by `search_check`, `!` is replaced by the configured prefix.
+const JUNK_QUERY_MESSAGE: &str =
+    "That doesn't look like a link or a search term, try !play <link> or !play <artist and title>";
+
+/// Single-pass EBU R128 loudness normalization, approximate but good enough for streaming.
+const LOUDNORM_FILTER: &str = "loudnorm=I=-16:TP=-1.5:LRA=11";
+
+static TRACK_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// TeamSpeak connectivity reported by `/health`.
+#[derive(Debug, Default)]
+struct ConnectionStatus {
+    connected: bool,
+    /// RFC 3339 timestamp of the last lost connection.
+    last_disconnect: Option<String>,
+}
+
+/// Counters exported at `/metrics`, the gauges are read from the shared state instead.
+#[derive(Debug, Default)]
+struct Metrics {
+    tracks_played: AtomicU64,
+    skips: AtomicU64,
+    errors: AtomicU64,
+}
+
+/// A running yt-dlp | ffmpeg chain together with the info json yt-dlp writes for it.
+#[derive(Debug)]
+struct Pipeline {
+    /// Local files are read by ffmpeg directly and have no yt-dlp.
+    ytdlp: Option<Child>,
+    ytdlp_stderr: Option<ChildStderr>,
+    ffmpeg: Child,
+    stdout: ChildStdout,
+    info_base: String,
+    info_path: String,
+    /// Playback speed the pipeline was spawned with, one second of output covers `speed` seconds.
+    speed: f32,
+}
+
+impl Pipeline {
+    async fn stop(&mut self, remove_info: bool) {
+        let info_path = if remove_info {
+            Some(self.info_path.as_str())
+        } else {
+            None
+        };
+        if let Some(ytdlp) = self.ytdlp.as_mut() {
+            cleanup_process(ytdlp, "yt-dlp", None).await;
+        }
+        cleanup_process(&mut self.ffmpeg, "ffmpeg", info_path).await;
+    }
+
+    /// The error yt-dlp reported if it exited unsuccessfully, only meaningful once its output ended.
+    async fn ytdlp_error(&mut self) -> Option<String> {
+        let ytdlp = self.ytdlp.as_mut()?;
+        let status = timeout(Duration::from_secs(1), ytdlp.wait())
+            .await
+            .ok()?
+            .ok()?;
+        if status.success() {
+            return None;
+        }
+
+        let mut stderr = String::new();
+        if let Some(mut ytdlp_stderr) = self.ytdlp_stderr.take() {
+            if let Err(e) = ytdlp_stderr.read_to_string(&mut stderr).await {
+                warn!("Failed to read stderr of yt-dlp: {}", e);
+            }
+        }
+        Some(ytdlp_error_message(&stderr))
+    }
+}
+
+/// Every track gets its own info json so metadata never leaks between tracks.
+fn new_info_base() -> String {
+    let info_base = env::temp_dir()
+        .join(format!(
+            "tsmusicbot-{}-{}",
+            std::process::id(),
+            TRACK_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ))
+        .display()
+        .to_string();
+
+    // A previous process with the same pid may have left its info json behind
+    let info_path = format!("{}.info.json", info_base);
+    if fs::metadata(&info_path).is_ok() {
+        if let Err(e) = fs::remove_file(&info_path) {
+            warn!("Failed to remove stale {}: {}", info_path, e);
+        }
+    }
+
+    info_base
+}
 
+fn build_ytdlp_args(
+    link: &str,
+    info_base: &str,
+    config: &Config,
+    socket_timeout: u32,
+) -> Vec<String> {
+    let socket_timeout = socket_timeout.to_string();
     // Extract Audio from Youtube using yt-dlp and pipe the output to stdout
-    let mut ytdlp_args = vec![
+    let mut ytdlp_args: Vec<String> = [
         "--quiet",
         "--extract-audio",
         "--audio-format",
         "opus",
         "--audio-quality",
-        "48K",
+        config.audio_bitrate.as_str(),
         "--buffer-size",
-        "16M",
+        config.buffer_size.as_str(),
         "--socket-timeout",
-        "5",
+        socket_timeout.as_str(),
         "--write-info-json",
         "--output",
         "-",
-    ];
+    ]
+    .iter()
+    .map(|arg| arg.to_string())
+    .collect();
+
+    ytdlp_args.push("--output".to_string());
+    ytdlp_args.push(format!("infojson:{}.%(ext)s", info_base));
 
-    if fs::metadata("cookies.txt").is_ok() {
-        ytdlp_args.push("--cookies");
-        ytdlp_args.push("cookies.txt");
+    if let Some(browser) = &config.cookies_from_browser {
+        ytdlp_args.push("--cookies-from-browser".to_string());
+        ytdlp_args.push(browser.to_string());
+    } else if let Some(cookies_file) = &config.cookies_file {
+        ytdlp_args.push("--cookies".to_string());
+        ytdlp_args.push(cookies_file.to_string());
+    } else if fs::metadata("cookies.txt").is_ok() {
+        ytdlp_args.push("--cookies".to_string());
+        ytdlp_args.push("cookies.txt".to_string());
     }
-    ytdlp_args.push(&link);
 
-    let mut ytdlp = match Command::new("yt-dlp")
-        .args(&ytdlp_args)
-        .stdout(Stdio::piped())
-        .spawn()
-    {
-        Err(why) => {
-            if let Err(e) = pkt_send.send(AudioPacket::None).await {
-                error!("Status packet sending error: {}", e);
-            }
-            panic!("couldn't spawn yt-dlp: {}", why);
+    // Treat anything that isn't a link as a YouTube search for the top result
+    if is_url(link) {
+        ytdlp_args.push(link.to_string());
+    } else {
+        ytdlp_args.push("--no-write-playlist-metafiles".to_string());
+        ytdlp_args.push(format!("ytsearch1:{}", link));
+    }
+
+    ytdlp_args
+}
+
+/// Spawns yt-dlp piped into ffmpeg, starting playback `start_secs` into the track.
+fn spawn_pipeline(
+    link: &str,
+    info_base: &str,
+    start_secs: u32,
+    config: &Config,
+    eq: EqPreset,
+    speed: f32,
+    socket_timeout: u32,
+) -> Result<Pipeline> {
+    let info_path = format!("{}.info.json", info_base);
+
+    // Local files go straight into ffmpeg, the info json yt-dlp would write is faked
+    let (ytdlp, ytdlp_stderr, input, ffmpeg_stdin) = match link.strip_prefix(LOCAL_PREFIX) {
+        Some(relative) => {
+            let music_dir = config
+                .music_dir
+                .as_deref()
+                .with_context(|| "no music_dir is configured")?;
+            let path = local_file_path(music_dir, relative)?;
+            let info_json = serde_json::json!({
+                "id": relative,
+                "title": local_title(relative),
+                "webpage_url": link,
+            });
+            fs::write(&info_path, info_json.to_string())
+                .with_context(|| format!("unable to write {}", info_path))?;
+            (None, None, path.display().to_string(), Stdio::null())
         }
-        Ok(process) => process,
-    };
+        None => {
+            let mut ytdlp = Command::new("yt-dlp")
+                .args(&build_ytdlp_args(link, info_base, config, socket_timeout))
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                // Don't leave yt-dlp running if ffmpeg can't be spawned
+                .kill_on_drop(true)
+                .spawn()
+                .with_context(|| "couldn't spawn yt-dlp")?;
 
-    let mut ffmpeg = match Command::new("ffmpeg")
-        .args(&[
-            "-loglevel",
-            "quiet",
-            "-i",
-            "pipe:0",
-            "-f",
-            "opus",
-            "-c:a",
-            "pcm_s16be",
-            "-f",
-            "s16be",
-            "pipe:1",
-        ])
-        .stdin(
-            ytdlp
+            let ytdlp_stdout: Stdio = ytdlp
                 .stdout
                 .take()
-                .unwrap_or_else(|| panic!("Failed to get stdout of yt-dlp")),
-        )
+                .with_context(|| "Failed to get stdout of yt-dlp")?
+                .try_into()
+                .with_context(|| "Failed to pipe stdout of yt-dlp")?;
+            let ytdlp_stderr = ytdlp.stderr.take();
+            (
+                Some(ytdlp),
+                ytdlp_stderr,
+                "pipe:0".to_string(),
+                ytdlp_stdout,
+            )
+        }
+    };
+
+    let start = start_secs.to_string();
+    let mut ffmpeg_args = vec![
+        "-loglevel",
+        "quiet",
+        "-ss",
+        start.as_str(),
+        "-i",
+        input.as_str(),
+    ];
+
+    let tempo_filter = atempo_filter(speed);
+    let mut filters: Vec<&str> = Vec::new();
+    if speed != 1.0 {
+        filters.push(tempo_filter.as_str());
+    }
+    if let Some(eq_filter) = eq.filter() {
+        filters.push(eq_filter);
+    }
+    if config.normalize {
+        filters.push(LOUDNORM_FILTER);
+    }
+    let filter_chain = filters.join(",");
+    if !filter_chain.is_empty() {
+        ffmpeg_args.push("-af");
+        ffmpeg_args.push(filter_chain.as_str());
+    }
+
+    // The frame buffers always hold interleaved stereo, mono sources are upmixed here
+    ffmpeg_args.extend(&[
+        "-f",
+        "opus",
+        "-c:a",
+        "pcm_s16be",
+        "-ac",
+        "2",
+        "-f",
+        "s16be",
+        "pipe:1",
+    ]);
+
+    let mut ffmpeg = Command::new("ffmpeg")
+        .args(&ffmpeg_args)
+        .stdin(ffmpeg_stdin)
         .stdout(Stdio::piped())
         .spawn()
-    {
-        Err(e) => panic!("couldn't spawn ffmpeg: {}", e),
-        Ok(process) => process,
+        .with_context(|| "couldn't spawn ffmpeg")?;
+
+    let stdout = ffmpeg
+        .stdout
+        .take()
+        .with_context(|| "Failed to get stdout of ffmpeg")?;
+
+    Ok(Pipeline {
+        ytdlp,
+        ytdlp_stderr,
+        ffmpeg,
+        stdout,
+        info_base: info_base.to_string(),
+        info_path,
+        speed,
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn play_file(
+    link: String,
+    requester: ClientId,
+    pkt_send: mpsc::Sender<AudioPacket>,
+    mut cmd_recv: mpsc::Receiver<PlayTaskCmd>,
+    volume: f32,
+    playback_state: Arc<Mutex<PlaybackState>>,
+    config: Arc<Config>,
+    start_secs: u32,
+    end_secs: Option<u32>,
+    pipeline: Option<Pipeline>,
+) {
+    const FRAME_SIZE: usize = 960;
+    const FRAME_DURATION: Duration = Duration::from_millis(20);
+    const SAMPLE_RATE: f64 = 48000.0;
+    const MAX_PACKET_SIZE: usize = 3 * 1276;
+
+    // TeamSpeak's voice codec is the mono variant of opus
+    let (codec, channels) = if config.mono {
+        (CodecType::OpusVoice, audiopus::Channels::Mono)
+    } else {
+        (CodecType::OpusMusic, audiopus::Channels::Stereo)
+    };
+    let mut current_volume = volume;
+    // Ramp up to current_volume over the first frames, volume changes only move the target
+    let fade_in_frames = config.fade_in_ms / 20;
+    // A handed over pipeline already faded in during the crossfade
+    let mut fade_frame: u32 = if pipeline.is_some() {
+        fade_in_frames
+    } else {
+        0
+    };
+    // Counts down the frames left once a stop was requested
+    let fade_out_frames = config.fade_out_ms / 20;
+    let mut fade_out_left: Option<u32> = None;
+    let crossfade_frames = config.crossfade_secs * 50;
+    let lookahead_secs = if config.crossfade_secs > 0 {
+        config.crossfade_secs
+    } else if config.prefetch {
+        PREFETCH_SECS
+    } else {
+        0
+    };
+    let mut paused = false;
+    let mut time_passed: f64 = start_secs as f64;
+    // Elapsed time is derived from the samples sent since the last (re)start of the pipeline
+    let mut start_position = start_secs;
+    let mut samples_sent: u64 = 0;
+    let mut retries_left = config.yt_dlp_retries;
+    let mut socket_timeout = config.socket_timeout_secs;
+    let mut timeout_extended = false;
+
+    let (eq, speed) = playback_state.lock().await.filters();
+    let mut pipeline = match pipeline {
+        Some(pipeline) => pipeline,
+        None => match spawn_pipeline(
+            &link,
+            &new_info_base(),
+            start_secs,
+            &config,
+            eq,
+            speed,
+            socket_timeout,
+        ) {
+            Err(why) => {
+                error!("Failed to start playback of {}: {:#}", link, why);
+                let msg = format!("{:#}", why);
+                for packet in [AudioPacket::Error(requester, msg), AudioPacket::None] {
+                    if let Err(e) = pkt_send.send(packet).await {
+                        error!("Status packet sending error: {}", e);
+                    }
+                }
+                return;
+            }
+            Ok(pipeline) => pipeline,
+        },
     };
+    let info_base = pipeline.info_base.clone();
+
+    let mut state = playback_state.lock().await;
+    state.time_passed = time_passed;
+    state.paused = paused;
+    state.link = Some(link.clone());
+    state.info_path = Some(pipeline.info_path.clone());
+    state.changed();
+    drop(state);
 
     // Setup Encoder
-    let encoder = audiopus::coder::Encoder::new(
-        audiopus::SampleRate::Hz48000,
-        audiopus::Channels::Stereo,
-        audiopus::Application::Audio,
-    )
-    .expect("Could not create encoder");
+    let application = if config.low_delay {
+        audiopus::Application::LowDelay
+    } else {
+        audiopus::Application::Audio
+    };
+    let mut encoder =
+        audiopus::coder::Encoder::new(audiopus::SampleRate::Hz48000, channels, application)
+            .expect("Could not create encoder");
+    if let Some(complexity) = config.opus_complexity {
+        if let Err(e) = encoder.set_complexity(complexity) {
+            error!("Failed to set opus complexity: {}", e);
+        }
+    }
+    if let Some(bitrate) = config.opus_bitrate {
+        if let Err(e) = encoder.set_bitrate(audiopus::Bitrate::BitsPerSecond(bitrate)) {
+            error!("Failed to set opus bitrate: {}", e);
+        }
+    }
 
+    let mut pcm_in_bytes: [u8; FRAME_SIZE * 4] = [0; FRAME_SIZE * 4];
     let mut pcm_in_be: [i16; FRAME_SIZE * 2] = [0; FRAME_SIZE * 2];
+    let mut pcm_mono: [i16; FRAME_SIZE] = [0; FRAME_SIZE];
     let mut opus_pkt: [u8; MAX_PACKET_SIZE] = [0; MAX_PACKET_SIZE];
 
-    let ffmpeg_stdout = &mut ffmpeg.stdout.take().unwrap();
+    // Crossfade into the next track, which is decoded next to the current one
+    let mut next_pcm_bytes: [u8; FRAME_SIZE * 4] = [0; FRAME_SIZE * 4];
+    let mut next_pcm: [i16; FRAME_SIZE * 2] = [0; FRAME_SIZE * 2];
+    let mut next_pipeline: Option<Pipeline> = None;
+    let mut next_requested = false;
+    let mut crossfade_frame: u32 = 0;
+    let mut duration: u32 = 0;
+    let mut info_loaded = false;
+    let mut stop_at: Option<u32> = end_secs;
+    let mut frames: u64 = 0;
 
-    let ideal_frame_duration = Duration::from_micros(18830);
-    let mut first_frame = true;
+    // Waiting for the first bytes of a track or a pause must not cause a burst of frames afterwards
+    let mut frame_timer = interval(FRAME_DURATION);
+    frame_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
     loop {
-        let frame_start_time = Instant::now();
+        frame_timer.tick().await;
 
-        let cmd: Option<PlayTaskCmd> = timeout(Duration::from_micros(1), cmd_recv.recv())
-            .await
-            .unwrap_or_else(|_| None);
+        let cmd: Option<PlayTaskCmd> = cmd_recv.try_recv().ok();
 
         match cmd {
             None => {}
             Some(PlayTaskCmd::ChangeVolume { modifier }) => {
                 current_volume = modifier;
             }
-            Some(PlayTaskCmd::Stop) => {
-                break;
+            Some(PlayTaskCmd::Stop { fade_out }) => {
+                // A second stop cuts the fade-out short
+                if !fade_out || paused || fade_out_frames == 0 || fade_out_left.is_some() {
+                    break;
+                }
+                fade_out_left = Some(fade_out_frames);
+            }
+            Some(PlayTaskCmd::Seek { position }) => {
+                debug!("Seeking to {}s", position);
+                if let Some(mut next) = next_pipeline.take() {
+                    next.stop(true).await;
+                }
+                next_requested = false;
+                pipeline.stop(false).await;
+                let (eq, speed) = playback_state.lock().await.filters();
+                match spawn_pipeline(
+                    &link,
+                    &info_base,
+                    position,
+                    &config,
+                    eq,
+                    speed,
+                    socket_timeout,
+                ) {
+                    Err(e) => {
+                        error!("Failed to restart pipeline for seek: {}", e);
+                        break;
+                    }
+                    Ok(new_pipeline) => {
+                        pipeline = new_pipeline;
+                        time_passed = position as f64;
+                        start_position = position;
+                        samples_sent = 0;
+                        fade_frame = 0;
+                    }
+                }
+            }
+            Some(PlayTaskCmd::PrepareNext {
+                link: next_link,
+                start_secs: next_start,
+            }) => {
+                if next_pipeline.is_none() {
+                    let (eq, speed) = playback_state.lock().await.filters();
+                    match spawn_pipeline(
+                        &next_link,
+                        &new_info_base(),
+                        next_start,
+                        &config,
+                        eq,
+                        speed,
+                        config.socket_timeout_secs,
+                    ) {
+                        Err(e) => error!("Failed to prepare {}: {}", next_link, e),
+                        Ok(next) => {
+                            debug!("Preparing {}", next_link);
+                            next_pipeline = Some(next);
+                            crossfade_frame = 0;
+                        }
+                    }
+                }
             }
             Some(PlayTaskCmd::Pause) => {
                 paused = true;
                 let mut state = playback_state.lock().await;
                 state.paused = paused;
+                state.changed();
                 drop(state);
             }
             Some(PlayTaskCmd::Resume) => {
                 paused = false;
                 let mut state = playback_state.lock().await;
                 state.paused = paused;
+                state.changed();
                 drop(state);
             }
         };
 
         if paused {
+            // Pausing during the fade-out would leave the track half faded
+            if fade_out_left.is_some() {
+                break;
+            }
             debug!("Paused wait...");
             sleep(Duration::from_millis(500)).await;
             continue;
         }
 
-        match ffmpeg_stdout.read_i16_into::<BigEndian>(&mut pcm_in_be) {
-            Err(e) => {
-                if e.kind() == ErrorKind::UnexpectedEof {
-                    debug!("ffmpeg_stdout: EOF");
-                } else {
-                    error!("Error ffmpeg_stdout: {}", e);
+        match read_frame(&mut pipeline.stdout, &mut pcm_in_bytes).await {
+            Ok(read) if read == pcm_in_bytes.len() => {
+                BigEndian::read_i16_into(&pcm_in_bytes, &mut pcm_in_be);
+            }
+            // The last frame is usually cut short, pad it with silence instead of dropping it,
+            // the next read then reports the end of the stream
+            Ok(read) if read > 0 => {
+                debug!("ffmpeg_stdout: partial frame of {} bytes", read);
+                pcm_in_bytes[read..].fill(0);
+                BigEndian::read_i16_into(&pcm_in_bytes, &mut pcm_in_be);
+            }
+            result => {
+                match result {
+                    Ok(_) => debug!("ffmpeg_stdout: EOF"),
+                    Err(e) => error!("Error ffmpeg_stdout: {}", e),
+                }
+                if let Some(why) = pipeline.ytdlp_error().await {
+                    // Only retry if nothing was played yet, otherwise the listeners would hear it twice
+                    let retry_delay =
+                        if samples_sent == 0 && !timeout_extended && is_socket_timeout(&why) {
+                            // Slow sources get one more chance with a longer timeout, apart from the retries
+                            timeout_extended = true;
+                            socket_timeout *= SOCKET_TIMEOUT_RETRY_FACTOR;
+                            warn!(
+                                "yt-dlp timed out for {}, retrying with a socket timeout of {}s",
+                                link, socket_timeout
+                            );
+                            Some(Duration::ZERO)
+                        } else if samples_sent == 0
+                            && retries_left > 0
+                            && is_transient_ytdlp_error(&why)
+                        {
+                            let attempt = config.yt_dlp_retries - retries_left + 1;
+                            retries_left -= 1;
+                            let delay = YTDLP_RETRY_DELAY * 2u32.pow(attempt - 1);
+                            warn!(
+                                "yt-dlp failed for {}: {}, retrying in {:?} (attempt {}/{})",
+                                link, why, delay, attempt, config.yt_dlp_retries
+                            );
+                            Some(delay)
+                        } else {
+                            None
+                        };
+                    if let Some(delay) = retry_delay {
+                        pipeline.stop(false).await;
+                        sleep(delay).await;
+                        let (eq, speed) = playback_state.lock().await.filters();
+                        match spawn_pipeline(
+                            &link,
+                            &info_base,
+                            start_position,
+                            &config,
+                            eq,
+                            speed,
+                            socket_timeout,
+                        ) {
+                            Err(e) => error!("Failed to restart pipeline for retry: {}", e),
+                            Ok(new_pipeline) => {
+                                pipeline = new_pipeline;
+                                fade_frame = 0;
+                                continue;
+                            }
+                        }
+                    }
+                    warn!("yt-dlp failed for {}: {}", link, why);
+                    if let Err(e) = pkt_send.send(AudioPacket::Error(requester, why)).await {
+                        error!("Status packet sending error: {}", e);
+                    }
                 }
                 break;
             }
-            Ok(_) => {}
         };
 
+        // A prefetched pipeline just waits until the current track ends
+        if let Some(next) = next_pipeline.as_mut().filter(|_| crossfade_frames > 0) {
+            match next.stdout.read_exact(&mut next_pcm_bytes).await {
+                Err(e) => {
+                    warn!("Next track ended during crossfade: {}", e);
+                    if let Some(mut next) = next_pipeline.take() {
+                        next.stop(true).await;
+                    }
+                }
+                Ok(_) => {
+                    BigEndian::read_i16_into(&next_pcm_bytes, &mut next_pcm);
+                    crossfade_frame += 1;
+                    let t = (crossfade_frame as f32 / crossfade_frames as f32).min(1.0);
+                    for i in 0..FRAME_SIZE * 2 {
+                        pcm_in_be[i] =
+                            (pcm_in_be[i] as f32 * (1.0 - t) + next_pcm[i] as f32 * t) as i16;
+                    }
+                }
+            }
+        }
+
         // adjust volume and encode in opus
+        let mut gain = if fade_frame < fade_in_frames {
+            fade_frame += 1;
+            volume_to_gain(current_volume) * fade_frame as f32 / fade_in_frames as f32
+        } else {
+            volume_to_gain(current_volume)
+        };
+        if let Some(left) = fade_out_left.as_mut() {
+            *left -= 1;
+            gain *= *left as f32 / fade_out_frames as f32;
+        }
         for i in 0..FRAME_SIZE * 2 {
-            pcm_in_be[i] = (pcm_in_be[i] as f32 * (current_volume * 0.2)) as i16;
+            pcm_in_be[i] =
+                (pcm_in_be[i] as f32 * gain).clamp(i16::MIN as f32, i16::MAX as f32) as i16;
         }
-        let len = encoder
-            .encode(&pcm_in_be, &mut opus_pkt[..])
-            .unwrap_or_else(|e| {
-                error!("Encoding error: {}", e);
-                0
-            });
+        let encoded = if config.mono {
+            for i in 0..FRAME_SIZE {
+                pcm_mono[i] = ((pcm_in_be[2 * i] as i32 + pcm_in_be[2 * i + 1] as i32) / 2) as i16;
+            }
+            encoder.encode(&pcm_mono, &mut opus_pkt[..])
+        } else {
+            encoder.encode(&pcm_in_be, &mut opus_pkt[..])
+        };
+        let len = encoded.unwrap_or_else(|e| {
+            error!("Encoding error: {}", e);
+            0
+        });
 
         let packet = OutAudio::new(&AudioData::C2S {
             id: 0,
@@ -258,21 +1380,72 @@ async fn play_file(
             }
             break;
         }
+        samples_sent += FRAME_SIZE as u64;
+        time_passed =
+            start_position as f64 + samples_sent as f64 / SAMPLE_RATE * pipeline.speed as f64;
 
-        let elapsed = frame_start_time.elapsed();
-        if let Some(remaining) = ideal_frame_duration.checked_sub(elapsed) {
-            sleep(remaining).await;
-        } else {
-            warn!(
-                "Audio processing lagged by {:?}",
-                elapsed - ideal_frame_duration
-            );
+        // The current track is fully faded out, the next one takes over from here
+        if crossfade_frames > 0 && next_pipeline.is_some() && crossfade_frame >= crossfade_frames {
+            break;
+        }
+        if fade_out_left == Some(0) {
+            break;
         }
 
-        if first_frame {
-            first_frame = false;
-        } else {
-            time_passed += Duration::from_micros(20031).as_secs_f64();
+        // yt-dlp writes the info json shortly after starting, poll it once a second until then
+        frames += 1;
+        if !info_loaded && frames % 50 == 0 {
+            if let Ok(info_json) = read_info_json(&pipeline.info_path) {
+                info_loaded = true;
+                duration = info_json.duration;
+                // A livestream never ends on its own and would block the queue forever
+                if info_json.is_live() {
+                    match config.max_live_secs {
+                        Some(max_live_secs) => {
+                            info!("{} is live, stopping it after {}s", link, max_live_secs);
+                            stop_at =
+                                Some(stop_at.map_or(max_live_secs, |end| end.min(max_live_secs)));
+                        }
+                        None => {
+                            info!("Refusing to play livestream {}", link);
+                            let msg = "Livestreams can't be played".to_string();
+                            if let Err(e) = pkt_send.send(AudioPacket::Error(requester, msg)).await
+                            {
+                                error!("Status packet sending error: {}", e);
+                            }
+                            break;
+                        }
+                    }
+                }
+                if let Some(end) = stop_at {
+                    if duration == 0 || end < duration {
+                        duration = end;
+                    }
+                }
+                if let Err(e) = pkt_send
+                    .send(AudioPacket::NowPlaying(info_json.title))
+                    .await
+                {
+                    error!("Status packet sending error: {}", e);
+                }
+            }
+        }
+
+        if let Some(end) = stop_at {
+            if time_passed >= end as f64 {
+                info!("Stopping {} at {}s", link, end);
+                break;
+            }
+        }
+
+        // Ask the main loop for the next track once the crossfade or prefetch window is reached
+        if lookahead_secs > 0 && !next_requested && fade_out_left.is_none() {
+            if duration > lookahead_secs && time_passed >= (duration - lookahead_secs) as f64 {
+                next_requested = true;
+                if let Err(e) = pkt_send.send(AudioPacket::RequestNext).await {
+                    error!("Status packet sending error: {}", e);
+                }
+            }
         }
 
         let playback_state_clone = Arc::clone(&playback_state);
@@ -283,20 +1456,140 @@ async fn play_file(
         });
     }
 
-    let mut state = playback_state.lock().await;
-    state.link = None;
-    state.time_passed = 0.0;
-    drop(state);
+    let mut state = playback_state.lock().await;
+    state.link = None;
+    state.info_path = None;
+    state.time_passed = 0.0;
+    state.changed();
+    drop(state);
+
+    debug!("Cleanup...");
+    // Hand a crossfading track over to the main loop so it keeps playing without a gap
+    let end_packet = match next_pipeline.take() {
+        Some(next) => AudioPacket::Handoff(next, crossfade_frame / 50),
+        None => AudioPacket::None,
+    };
+    if let Err(e) = pkt_send.send(end_packet).await {
+        error!("Status packet sending error: {}", e);
+        return;
+    }
+    cmd_recv.close();
+
+    pipeline.stop(true).await;
+}
+
+/// Spawns the play task for `entry` and returns the channel used to control it.
+/// Playback starts at `start_secs`, but never before the start of the track's clip.
+fn start_playback(
+    entry: &QueueEntry,
+    pkt_send: &mpsc::Sender<AudioPacket>,
+    volume: f32,
+    playback_state: &Arc<Mutex<PlaybackState>>,
+    config: &Arc<Config>,
+    start_secs: u32,
+    pipeline: Option<Pipeline>,
+) -> mpsc::Sender<PlayTaskCmd> {
+    let audio_task_pkt_send = pkt_send.clone();
+    let (task_cmd_send, task_cmd_recv) = mpsc::channel(4);
+    let playback_state_clone = Arc::clone(playback_state);
+    let config_clone = Arc::clone(config);
+    let link = entry.link.clone();
+    // Tracks restored from the queue state have no requester to report errors to
+    let requester = entry.requester_id.unwrap_or(HTTP_CLIENT_ID);
+    let volume = entry.volume.unwrap_or(volume);
+    let start_secs = start_secs.max(entry.start.unwrap_or(0));
+    let end_secs = entry.end;
+
+    tokio::spawn(async move {
+        play_file(
+            link,
+            requester,
+            audio_task_pkt_send,
+            task_cmd_recv,
+            volume,
+            playback_state_clone,
+            config_clone,
+            start_secs,
+            end_secs,
+            pipeline,
+        )
+        .await;
+    });
+
+    task_cmd_send
+}
+
+/// Recreates the TeamSpeak connection with exponential backoff until it succeeds or
+/// `MAX_RECONNECT_ATTEMPTS` consecutive attempts have failed.
+/// Connects with `name` instead of the configured name, so a `!name` survives the reconnect.
+async fn reconnect(config: &Config, name: &str) -> Result<Connection> {
+    let config = Config {
+        name: name.to_string(),
+        ..config.clone()
+    };
+    let mut delay = RECONNECT_BASE_DELAY;
+
+    for attempt in 1..=MAX_RECONNECT_ATTEMPTS {
+        warn!(
+            "Reconnecting to TeamSpeak Server in {:?} (attempt {}/{})",
+            delay, attempt, MAX_RECONNECT_ATTEMPTS
+        );
+        sleep(delay).await;
+
+        match connect_to_ts(config.clone()) {
+            Ok(mut con) => match wait_for_connection(&mut con).await {
+                Ok(()) => {
+                    info!("Reconnected to TeamSpeak Server");
+                    return Ok(con);
+                }
+                Err(e) => warn!("Reconnect attempt {} failed: {}", attempt, e),
+            },
+            Err(e) => warn!("Reconnect attempt {} failed: {}", attempt, e),
+        }
+
+        delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+    }
+
+    bail!(
+        "Giving up after {} failed reconnect attempts",
+        MAX_RECONNECT_ATTEMPTS
+    );
+}
 
-    debug!("Cleanup...");
-    if let Err(e) = pkt_send.send(AudioPacket::None).await {
-        error!("Status packet sending error: {}", e);
-        return;
+/// `--check` runs everything the bot needs before connecting, reports each step and fails if any
+/// of them did.
+async fn run_check(config_path: Option<&str>) -> Result<()> {
+    let mut failed = false;
+    let mut report = |result: Result<String>| match result {
+        Ok(msg) => println!("[ok] {}", msg),
+        Err(e) => {
+            failed = true;
+            println!("[error] {:#}", e);
+        }
+    };
+
+    report(check_dependencies().map(|()| "ffmpeg and yt-dlp found".to_string()));
+    let config = read_config(config_path).and_then(|config| config.validate().map(|()| config));
+    match config {
+        Ok(config) => {
+            let config_path = config_path.unwrap_or(DEFAULT_CONFIG_FILE);
+            report(Ok(format!("{} is valid", config_path)));
+            match split_hostname(&config.host) {
+                Some((name, _)) => {
+                    let ip = lookup_ip(name, config.ip_version).await;
+                    report(ip.map(|ip| format!("{} resolves to {}", name, ip)));
+                }
+                None => report(Ok(format!("{} is an IP address", config.host))),
+            }
+        }
+        Err(e) => report(Err(e)),
     }
-    cmd_recv.close();
 
-    cleanup_process(&mut ytdlp, "yt-dlp").await;
-    cleanup_process(&mut ffmpeg, "ffmpeg").await;
+    if failed {
+        bail!("Check failed");
+    }
+    println!("Check passed");
+    Ok(())
 }
 
 #[tokio::main]
@@ -305,30 +1598,40 @@ async fn main() -> Result<()> {
 }
 
 async fn real_main() -> Result<()> {
-    env_logger::init();
+    let started = Instant::now();
+    init_logger();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    // Only the default config file may be missing
+    let config_path = args
+        .iter()
+        .find(|arg| !arg.starts_with("--"))
+        .map(String::as_str);
+    if args.iter().any(|arg| arg == "--check") {
+        return run_check(config_path).await;
+    }
 
-    check_dependencies();
+    check_dependencies()?;
 
-    let mut config_json: Config = read_config("config.json");
+    let mut config_json: Config = read_config(config_path)?;
+    config_json.validate()?;
 
-    let pre_resolve = env::var("PRE_RESOLVE_HOST")
-        .map(|v| v.to_lowercase() == "true")
-        .unwrap_or(false);
+    // PRE_RESOLVE_HOST is the older way to turn this on
+    let pre_resolve = config_json.resolve_host
+        || env::var("PRE_RESOLVE_HOST")
+            .map(|v| v.to_lowercase() == "true")
+            .unwrap_or(false);
 
     if pre_resolve {
-        config_json.host = resolve_host(&config_json.host).await?;
+        config_json.host = resolve_host(&config_json.host, config_json.ip_version).await?;
     }
 
-    let mut init_con: Connection = connect_to_ts(config_json);
+    let config_json = Arc::new(config_json);
+    let http_host = config_json.http_host.clone();
+    let http_port = config_json.http_port;
 
-    let r = init_con
-        .events()
-        .try_filter(|e| future::ready(matches!(e, StreamItem::BookEvents(_))))
-        .next()
-        .await;
-    if let Some(r) = r {
-        r?;
-    }
+    let mut init_con: Connection = connect_to_ts((*config_json).clone())?;
+    wait_for_connection(&mut init_con).await?;
 
     info!("Connected to TeamSpeak Server");
 
@@ -336,72 +1639,282 @@ async fn real_main() -> Result<()> {
     let (status_send, mut status_recv) = mpsc::channel(64);
     let mut playing: bool = false;
     let mut paused: bool = false;
+    let mut muted = false;
+    let mut quiet = config_json.quiet;
+    // Shown while nothing is playing, changed with `!name`
+    let mut ts_name = config_json.name.clone();
+    let mut pending_announce = PendingAnnouncement::default();
     let mut volume: f32 = DEFAULT_VOLUME;
-    let mut current_playing_link: Option<String> = None;
+    let mut current_track: Option<QueueEntry> = None;
+    let mut repeat_mode = RepeatMode::Off;
+    let mut skip_requested = false;
+    let mut skip_votes = SkipVotes::default();
+    // Only a queue that ran out on its own is announced as finished
+    let mut stop_requested = false;
+    let mut shuffle = false;
+    let mut search_results: HashMap<ClientId, Vec<String>> = HashMap::new();
+    let mut auto_paused = false;
+    let mut listeners_check: Option<Instant> = None;
+    let idle_timeout = config_json.idle_timeout_secs.map(Duration::from_secs);
+    let mut last_activity = Instant::now();
+    let mut idle = false;
+    // Recent command times per user, only touched from the event stream
+    let command_times: Mutex<HashMap<ClientId, VecDeque<Instant>>> = Mutex::new(HashMap::new());
+    // Replies go to where the last command of a user came from, only touched from the event stream
+    let reply_targets: Mutex<HashMap<ClientId, MessageTarget>> = Mutex::new(HashMap::new());
+    // While set, only this client and admins may control playback
+    let dj: Mutex<Option<ClientId>> = Mutex::new(None);
+    let rate_limit_window = Duration::from_secs(config_json.rate_limit_secs);
+    let mut crossfade_track: Option<QueueEntry> = None;
 
     let (mut cmd_send, _cmd_recv) = mpsc::channel(4);
-    let mut play_queue: VecDeque<String> = VecDeque::new();
+    let saved_state = read_queue_state(QUEUE_STATE_FILE);
+    let mut play_queue: VecDeque<QueueEntry> = saved_state.queue;
+    let mut history: VecDeque<HistoryEntry> = saved_state.history;
+    let mut saved_current = saved_state.current.clone();
+    let mut saved_queue = play_queue.clone();
+    let queue_snapshot = Arc::new(Mutex::new(play_queue.clone()));
 
     let playback_state = Arc::new(Mutex::new(PlaybackState {
         time_passed: 0.0,
         paused: false,
+        muted: false,
         link: None,
+        info_path: None,
+        repeat: RepeatMode::Off,
+        eq: EqPreset::Flat,
+        speed: 1.0,
+        volume,
+        events: broadcast::channel(16).0,
     }));
 
     let playback_state_clone1 = Arc::clone(&playback_state);
-    let playback_state_clone2 = Arc::clone(&playback_state);
+    let control_status_send = status_send.clone();
+    let queue_snapshot_clone = Arc::clone(&queue_snapshot);
+    let metrics = Arc::new(Metrics::default());
+    let connection_status = Arc::new(Mutex::new(ConnectionStatus {
+        connected: true,
+        last_disconnect: None,
+    }));
+    let connection_status_clone = Arc::clone(&connection_status);
+    let metrics_clone = Arc::clone(&metrics);
+    let metrics_queue_snapshot = Arc::clone(&queue_snapshot);
+    let bot_name = config_json.name.clone();
+    let http_message = config_json.http_message.clone();
+    let http_token = config_json.http_token.clone();
+
+    // Resolves the titles of queued links in the background, one at a time
+    let metadata_cache = Arc::new(Mutex::new(MetadataCache::default()));
+    let queue_metadata_cache = Arc::clone(&metadata_cache);
+    let status_sources = StatusSources {
+        playback: Arc::clone(&playback_state),
+        queue: Arc::clone(&queue_snapshot),
+        metadata_cache: Arc::clone(&metadata_cache),
+    };
+    let (metadata_send, mut metadata_recv) = mpsc::channel::<String>(64);
+    {
+        let metadata_cache = Arc::clone(&metadata_cache);
+        tokio::spawn(async move {
+            while let Some(link) = metadata_recv.recv().await {
+                if metadata_cache.lock().await.get(&link).is_some() {
+                    continue;
+                }
+                match fetch_metadata(&link).await {
+                    Ok(info_json) => metadata_cache.lock().await.insert(link, info_json),
+                    Err(e) => debug!("Failed to fetch metadata of {}: {:#}", link, e),
+                }
+                sleep(METADATA_FETCH_DELAY).await;
+            }
+        });
+    }
     tokio::spawn(async move {
         let (layer, io) = SocketIo::new_layer();
-        io.ns("/", |s: SocketRef| {
+        let socket_status_sources = status_sources.clone();
+        io.ns("/", move |s: SocketRef| {
             info!("Client connected to Socket");
             s.on_disconnect(|| {
                 info!("Client disconnected");
             });
 
+            let status_sources = socket_status_sources.clone();
             tokio::spawn(async move {
                 let mut interval = tokio::time::interval(Duration::from_secs(1));
                 loop {
                     interval.tick().await;
-                    let status = get_status(State(Arc::clone(&playback_state_clone2))).await;
+                    let status = get_status(State(status_sources.clone())).await;
                     s.emit("status", &status.to_string()).ok();
                 }
             });
         });
 
         let app = Router::new()
-            .route("/", get(|| async { "TSMusicbot is running!" }))
+            .route("/", get(move || get_root(bot_name, http_message, started)))
             .route(
                 "/status",
+                get({
+                    let status_sources = status_sources.clone();
+                    move || get_status(State(status_sources))
+                }),
+            )
+            .route(
+                "/ws",
+                get({
+                    let status_sources = status_sources.clone();
+                    move |ws: WebSocketUpgrade| ws_status(ws, status_sources)
+                }),
+            )
+            .route(
+                "/queue",
+                get(move || get_queue(State(queue_snapshot_clone), queue_metadata_cache)),
+            )
+            .route(
+                "/metrics",
                 get({
                     let playback_state_clone = Arc::clone(&playback_state_clone1);
-                    move || get_status(State(playback_state_clone))
+                    move || get_metrics(metrics_clone, playback_state_clone, metrics_queue_snapshot)
+                }),
+            )
+            .route(
+                "/control",
+                post(move |body: axum::Json<ControlRequest>| {
+                    control(body, control_status_send, status_sources)
                 }),
             )
-            .layer(layer);
+            .layer(layer)
+            // Covers the socket.io handshake as well, only routes added below stay open
+            .layer(middleware::from_fn_with_state(http_token, require_token))
+            .route("/health", get(move || get_health(connection_status_clone)));
 
-        let listener = tokio::net::TcpListener::bind("0.0.0.0:3000")
+        let listener = tokio::net::TcpListener::bind((http_host.as_str(), http_port))
             .await
-            .unwrap_or_else(|e| panic!("Failed to bind to 0.0.0.0:3000: {}", e));
+            .unwrap_or_else(|e| panic!("Failed to bind to {}:{}: {}", http_host, http_port, e));
+        info!("HTTP server started on {}:{}", http_host, http_port);
         axum::serve(listener, app)
             .await
             .unwrap_or_else(|e| panic!("Failed to start http server: {}", e));
-        info!("HTTP server started on 0.0.0.0:3000");
     });
 
+    // Resume the track that was playing before the restart
+    if let Some(entry) = saved_state.current {
+        info!(
+            "Resuming {} at {}s with {} queued tracks",
+            entry.link,
+            saved_state.position as u32,
+            play_queue.len()
+        );
+        playing = true;
+        cmd_send = start_playback(
+            &entry,
+            &pkt_send,
+            volume,
+            &playback_state,
+            &config_json,
+            saved_state.position as u32,
+            None,
+        );
+        current_track = Some(entry);
+    }
+
+    // Docker and systemd stop the bot with SIGTERM, which only exists on unix
+    let sigterm = Arc::new(Notify::new());
+    #[cfg(unix)]
+    {
+        let mut signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        let sigterm = Arc::clone(&sigterm);
+        tokio::spawn(async move {
+            signal.recv().await;
+            sigterm.notify_one();
+        });
+    }
+
     loop {
         let events = init_con.events().try_for_each(|e| async {
             match e {
                 StreamItem::BookEvents(msg_vec) => {
                     for msg in msg_vec {
+                        // The DJ gives up control by leaving the server
+                        if let Event::PropertyRemoved {
+                            id: PropertyId::Client(client_id),
+                            ..
+                        } = &msg
+                        {
+                            let mut dj = dj.lock().await;
+                            if *dj == Some(*client_id) {
+                                info!("The DJ left, ending DJ mode");
+                                *dj = None;
+                            }
+                        }
                         match msg {
                             Event::Message {
                                 invoker: user,
-                                target: _,
+                                target,
                                 message,
                             } => {
-                                if let Err(e) =
-                                    status_send.send(parse_command(&message, user.id)).await
+                                let mut action =
+                                    parse_command(&message, user.id, &config_json.prefix, target);
+                                reply_targets
+                                    .lock()
+                                    .await
+                                    .insert(user.id, reply_target(target, user.id));
+                                let uid = user.uid.as_ref().map(|uid| uid.as_ref().to_string());
+                                // With skip votes everyone may vote, admins still skip right away
+                                let skip_vote =
+                                    config_json.skip_votes > 1 && matches!(action, Action::Skip(_));
+                                if !skip_vote
+                                    && !is_permitted(&action, uid.as_deref(), &config_json.admins)
+                                {
+                                    info!("{} is not permitted to use {:?}", user.name, action);
+                                    action = Action::NotPermitted(user.id);
+                                }
+                                if is_dj_restricted(&action) {
+                                    let current_dj = *dj.lock().await;
+                                    if current_dj.is_some_and(|dj| dj != user.id)
+                                        && !is_admin(uid.as_deref(), &config_json.admins)
+                                    {
+                                        info!("{} is not the DJ", user.name);
+                                        action = Action::NotDj(user.id);
+                                    }
+                                }
+                                // Plain chat messages are not commands and never count
+                                if config_json.rate_limit_commands > 0
+                                    && !matches!(action, Action::None)
                                 {
+                                    let now = Instant::now();
+                                    let mut command_times = command_times.lock().await;
+                                    command_times.retain(|_, times| {
+                                        times.back().is_some_and(|time| {
+                                            now.duration_since(*time) < rate_limit_window
+                                        })
+                                    });
+                                    let times = command_times.entry(user.id).or_default();
+                                    if !within_rate_limit(
+                                        times,
+                                        now,
+                                        config_json.rate_limit_commands,
+                                        rate_limit_window,
+                                    ) {
+                                        info!("Rate limiting {}", user.name);
+                                        action = Action::RateLimited(user.id);
+                                    }
+                                }
+                                if let Err(e) = status_send.send(action).await {
+                                    error!("Status packet sending error: {}", e);
+                                }
+                            }
+                            // Clients joining, leaving or switching channels
+                            Event::PropertyAdded {
+                                id: PropertyId::Client(_),
+                                ..
+                            }
+                            | Event::PropertyRemoved {
+                                id: PropertyId::Client(_),
+                                ..
+                            }
+                            | Event::PropertyChanged {
+                                id: PropertyId::ClientChannel(_),
+                                ..
+                            } if config_json.auto_pause => {
+                                if let Err(e) = status_send.send(Action::ListenersChanged).await {
                                     error!("Status packet sending error: {}", e);
                                 }
                             }
@@ -414,69 +1927,254 @@ async fn real_main() -> Result<()> {
             Ok(())
         });
 
-        let mut sigterm = tokio::signal::unix::signal(SignalKind::terminate())?;
-
         tokio::select! {
             val = status_recv.recv() => {
                 match val {
                     None => {
                     },
                     Some(action) => {
+                        if !matches!(action, Action::ListenersChanged) {
+                            last_activity = Instant::now();
+                            idle = false;
+                        }
+                        let reply_targets = reply_targets.lock().await.clone();
+                        let reply_to = |user_id: ClientId| {
+                            reply_targets.get(&user_id).copied().unwrap_or(MessageTarget::Client(user_id))
+                        };
+                        let vote_only = matches!(action, Action::VoteSkip(_));
                         match action {
-                            Action::PlayAudio(link, user_id) => {
+                            Action::PlayAudio(link, options, user_id) => {
                                 debug!("Playing");
+                                if config_json.follow {
+                                    follow_client(&mut init_con, user_id);
+                                }
+                                let known_duration = metadata_cache.lock().await.duration(&link);
                                 let msg: String;
-                                if !playing {
-                                    playing = true;
-                                    paused = false;
-                                    let audio_task_pkt_send = pkt_send.clone();
-
-                                    let (task_cmd_send,  task_cmd_recv) = mpsc::channel(4);
-
-                                    cmd_send = task_cmd_send;
-
-                                    current_playing_link = Some(link.clone());
-                                    let playback_state_clone = Arc::clone(&playback_state);
+                                let mut routine = false;
+                                if !is_fetchable(&link, config_json.search_check) {
+                                    msg = JUNK_QUERY_MESSAGE.replace('!', &config_json.prefix);
+                                } else if let Err(why) = validate_clip(&options, known_duration) {
+                                    msg = why;
+                                } else if config_json.dedupe && is_queued(&link, current_track.as_ref(), &play_queue) {
+                                    msg = "Already queued".to_string();
+                                } else if is_playlist(&link) {
+                                    let playlist_status_send = status_send.clone();
                                     tokio::spawn(async move {
-                                        play_file(link, audio_task_pkt_send, task_cmd_recv, volume, playback_state_clone).await;
+                                        let result = fetch_playlist_entries(&link).await;
+                                        let links = match result {
+                                            Ok(links) if !links.is_empty() => links,
+                                            Ok(_) => vec![link],
+                                            Err(e) => {
+                                                warn!("Failed to expand playlist, playing as single link: {}", e);
+                                                vec![link]
+                                            }
+                                        };
+                                        if let Err(e) = playlist_status_send.send(Action::EnqueuePlaylist(links, user_id)).await {
+                                            error!("Status packet sending error: {}", e);
+                                        }
                                     });
+                                    msg = "Loading playlist...".to_string();
+                                    routine = true;
+                                } else if !playing {
+                                    playing = true;
+                                    paused = false;
+                                    let entry = queue_entry(&init_con, link, user_id).with_options(options);
+                                    cmd_send = start_playback(&entry, &pkt_send, volume, &playback_state, &config_json, 0, None);
+                                    current_track = Some(entry);
+                                    skip_votes.clear();
                                     msg = "Playing Link".to_string();
+                                    routine = true;
+                                } else if queue_space_left(&play_queue, config_json.max_queue_size) == 0 {
+                                    msg = "The queue is full".to_string();
+                                } else if queue_slots_left(&play_queue, user_id, config_json.max_queue_per_user) == 0 {
+                                    msg = "You reached the maximum number of queued tracks".to_string();
                                 } else {
-                                    play_queue.push_back(link);
+                                    play_queue.push_back(queue_entry(&init_con, link, user_id).with_options(options));
                                     msg = "Queued Link".to_string();
+                                    routine = true;
+                                }
+                                send_confirmation(&mut init_con, reply_to(user_id), &msg, quiet && routine);
+                            },
+                            Action::PlayLocal(path, user_id) => {
+                                debug!("Play local");
+                                match config_json.music_dir.as_deref().map(|music_dir| local_file_path(music_dir, &path)) {
+                                    None => send_ts_message(&mut init_con, reply_to(user_id), "No music directory is configured"),
+                                    Some(Err(e)) => send_ts_message(&mut init_con, reply_to(user_id), &format!("Can't play {}: {:#}", path, e)),
+                                    Some(Ok(_)) => {
+                                        let link = format!("{}{}", LOCAL_PREFIX, path);
+                                        if let Err(e) = status_send.send(Action::PlayAudio(link, TrackOptions::default(), user_id)).await {
+                                            error!("Status packet sending error: {}", e);
+                                        }
+                                    }
+                                }
+                            },
+                            Action::EnqueuePlaylist(links, user_id) => {
+                                debug!("Enqueue playlist");
+                                // The first track is played right away if nothing is playing, it does not count towards the limit
+                                let user_slots = queue_slots_left(&play_queue, user_id, config_json.max_queue_per_user);
+                                let space = queue_space_left(&play_queue, config_json.max_queue_size);
+                                let slots = user_slots.min(space).saturating_add(if playing { 0 } else { 1 });
+                                let skipped = links.len().saturating_sub(slots);
+                                let count = links.len() - skipped;
+                                let entries: Vec<QueueEntry> = links.into_iter().take(slots).map(|link| queue_entry(&init_con, link, user_id)).collect();
+                                play_queue.extend(entries);
+                                if !playing {
+                                    if let Some(entry) = play_queue.pop_front() {
+                                        playing = true;
+                                        paused = false;
+                                        cmd_send = start_playback(&entry, &pkt_send, volume, &playback_state, &config_json, 0, None);
+                                        current_track = Some(entry);
+                                        skip_votes.clear();
+                                    }
+                                }
+                                let msg = if skipped > 0 && space < user_slots {
+                                    format!("Added {} tracks to the queue, skipped {} because the queue is full", count, skipped)
+                                } else if skipped > 0 {
+                                    format!("Added {} tracks to the queue, skipped {} because you reached the maximum number of queued tracks", count, skipped)
+                                } else {
+                                    format!("Added {} tracks to the queue", count)
+                                };
+                                send_confirmation(&mut init_con, reply_to(user_id), &msg, quiet && skipped == 0);
+                            },
+                            Action::Search(query, user_id) => {
+                                debug!("Search");
+                                let search_status_send = status_send.clone();
+                                tokio::spawn(async move {
+                                    let results = search(&query, SEARCH_RESULTS).await.unwrap_or_else(|e| {
+                                        warn!("Search failed: {}", e);
+                                        Vec::new()
+                                    });
+                                    if let Err(e) = search_status_send.send(Action::SearchResults(results, user_id)).await {
+                                        error!("Status packet sending error: {}", e);
+                                    }
+                                });
+                                send_confirmation(&mut init_con, reply_to(user_id), "Searching...", quiet);
+                            },
+                            Action::SearchResults(results, user_id) => {
+                                debug!("Search results");
+                                let msg = if results.is_empty() {
+                                    "No results found".to_string()
+                                } else {
+                                    let mut msg = "\nSearch results:\n".to_owned();
+                                    for (i, info_json) in results.iter().enumerate() {
+                                        msg.push_str(&format!("{}. {} ({})\n", i + 1, info_json.title, format_duration(info_json.duration)));
+                                    }
+                                    msg.push_str(&format!("Use {}pick <n> to play a result", config_json.prefix));
+                                    msg
+                                };
+                                search_results.insert(user_id, results.into_iter().map(|info_json| info_json.webpage_url).collect());
+                                send_ts_message(&mut init_con, reply_to(user_id), &msg);
+                            },
+                            Action::Pick(index, user_id) => {
+                                debug!("Pick");
+                                let link = search_results.get(&user_id).and_then(|links| links.get(index.wrapping_sub(1))).cloned();
+                                match link {
+                                    Some(link) => {
+                                        if let Err(e) = status_send.send(Action::PlayAudio(link, TrackOptions::default(), user_id)).await {
+                                            error!("Status packet sending error: {}", e);
+                                        }
+                                    }
+                                    None => send_ts_message(&mut init_con, reply_to(user_id), "No such search result"),
                                 }
-                                send_ts_message(&mut init_con, MessageTarget::Client(user_id), &msg);
                             },
                             Action::ChangeVolume {modifier, user_id} => {
                                 debug!("Change volume");
                                 let msg: String;
-                                if modifier > 0.0 && modifier <= 1.0 {
+                                let changed = modifier.is_some();
+                                if let Some(modifier) = modifier {
                                     volume = modifier;
+                                    let mut state = playback_state.lock().await;
+                                    state.volume = volume;
+                                    state.changed();
+                                    drop(state);
                                     if playing { let _ = cmd_send.send(PlayTaskCmd::ChangeVolume {modifier}).await; };
                                     msg = format!("Volume set to: {}", (modifier * 100.0).floor());
                                 } else {
                                     msg = format!("Current Volume: {}", (volume * 100.0).floor());
                                 }
-                                send_ts_message(&mut init_con, MessageTarget::Client(user_id), &msg);
+                                send_confirmation(&mut init_con, reply_to(user_id), &msg, quiet && changed);
+                            },
+                            Action::AdjustVolume {direction, user_id} => {
+                                debug!("Adjust volume");
+                                let percent = ((volume * 100.0).round() as i32 + direction * config_json.volume_step as i32).clamp(0, 100);
+                                let modifier = percent as f32 / 100.0;
+                                volume = modifier;
+                                let mut state = playback_state.lock().await;
+                                state.volume = volume;
+                                state.changed();
+                                drop(state);
+                                if playing { let _ = cmd_send.send(PlayTaskCmd::ChangeVolume {modifier}).await; };
+                                let msg = format!("Volume set to: {}", percent);
+                                send_confirmation(&mut init_con, reply_to(user_id), &msg, quiet);
                             },
-                            Action::QueueNextAudio(link, user_id) => {
+                            Action::QueueNextAudio(link, options, user_id) => {
                                 debug!("Queued");
-                                if playing {
-                                    play_queue.push_front(link);
-                                    send_ts_message(&mut init_con, MessageTarget::Client(user_id), "Queued Link");
+                                let known_duration = metadata_cache.lock().await.duration(&link);
+                                if !is_fetchable(&link, config_json.search_check) {
+                                    send_ts_message(&mut init_con, reply_to(user_id), &JUNK_QUERY_MESSAGE.replace('!', &config_json.prefix));
+                                } else if let Err(why) = validate_clip(&options, known_duration) {
+                                    send_ts_message(&mut init_con, reply_to(user_id), &why);
+                                } else if config_json.dedupe && is_queued(&link, current_track.as_ref(), &play_queue) {
+                                    send_ts_message(&mut init_con, reply_to(user_id), "Already queued");
+                                } else if !playing {
+                                    playing = true;
+                                    paused = false;
+                                    let entry = queue_entry(&init_con, link, user_id).with_options(options);
+                                    cmd_send = start_playback(&entry, &pkt_send, volume, &playback_state, &config_json, 0, None);
+                                    current_track = Some(entry);
+                                    skip_votes.clear();
+                                    send_confirmation(&mut init_con, reply_to(user_id), "Playing Link", quiet);
+                                } else if queue_space_left(&play_queue, config_json.max_queue_size) == 0 {
+                                    send_ts_message(&mut init_con, reply_to(user_id), "The queue is full");
+                                } else if queue_slots_left(&play_queue, user_id, config_json.max_queue_per_user) == 0 {
+                                    send_ts_message(&mut init_con, reply_to(user_id), "You reached the maximum number of queued tracks");
                                 } else {
-                                    Action::PlayAudio(link, user_id);
+                                    play_queue.push_front(queue_entry(&init_con, link, user_id).with_options(options));
+                                    send_confirmation(&mut init_con, reply_to(user_id), "Queued Link", quiet);
                                 }
                             },
-                            Action::Skip => {
+                            // Both commands add to the same tally, only their threshold differs
+                            Action::Skip(user_id) | Action::VoteSkip(user_id) => {
                                 debug!("Skip");
-                                if playing {
+                                // The requester of the track, admins and the HTTP API don't need votes
+                                let instant = !vote_only && (config_json.skip_votes <= 1
+                                    || user_id == HTTP_CLIENT_ID
+                                    || current_track.as_ref().is_some_and(|track| track.requester_id == Some(user_id))
+                                    || is_admin(client_uid(&init_con, user_id).as_deref(), &config_json.admins));
+                                let mut enough_votes = true;
+                                if playing && !instant {
+                                    let counted = skip_votes.vote(user_id);
+                                    let listeners = count_listeners(&init_con);
+                                    let required = if vote_only {
+                                        required_vote_ratio(config_json.skip_ratio, listeners)
+                                    } else {
+                                        required_skip_votes(config_json.skip_votes, listeners)
+                                    };
+                                    enough_votes = skip_votes.count() >= required;
+                                    if !enough_votes {
+                                        let msg = if counted {
+                                            format!("{}/{} votes to skip", skip_votes.count(), required)
+                                        } else {
+                                            format!("You already voted to skip this track ({}/{} votes)", skip_votes.count(), required)
+                                        };
+                                        send_ts_message(&mut init_con, reply_to(user_id), &msg);
+                                    }
+                                }
+                                if playing && enough_votes {
+                                    skip_votes.clear();
+                                    metrics.skips.fetch_add(1, Ordering::Relaxed);
                                     paused = false;
-                                    let _ = cmd_send.send(PlayTaskCmd::Stop).await;
+                                    skip_requested = true;
+                                    // Crossfading into the same track again is not a skip
+                                    if repeat_mode == RepeatMode::One {
+                                        crossfade_track = None;
+                                    }
+                                    let _ = cmd_send.send(PlayTaskCmd::Stop { fade_out: true }).await;
                                 };
                             },
                             Action::Resume => {
                                 debug!("Resume");
+                                auto_paused = false;
                                 if playing && paused {
                                     paused = false;
                                     let _ = cmd_send.send(PlayTaskCmd::Resume).await;
@@ -489,36 +2187,446 @@ async fn real_main() -> Result<()> {
                                     let _ = cmd_send.send(PlayTaskCmd::Pause).await;
                                 };
                             },
+                            Action::TogglePause(user_id) => {
+                                debug!("Toggle pause");
+                                let msg = if playing {
+                                    paused = !paused;
+                                    if paused {
+                                        let _ = cmd_send.send(PlayTaskCmd::Pause).await;
+                                        "Paused"
+                                    } else {
+                                        let _ = cmd_send.send(PlayTaskCmd::Resume).await;
+                                        "Resumed"
+                                    }
+                                } else {
+                                    "Nothing is playing"
+                                };
+                                send_confirmation(&mut init_con, reply_to(user_id), msg, quiet && playing);
+                            },
                             Action::Stop => {
                                 debug!("Stop");
                                 if playing {
                                     paused = false;
                                     play_queue.clear();
-                                    let _ = cmd_send.send(PlayTaskCmd::Stop).await;
+                                    current_track = None;
+                                    crossfade_track = None;
+                                    stop_requested = true;
+                                    let _ = cmd_send.send(PlayTaskCmd::Stop { fade_out: true }).await;
                                 };
                             },
                             Action::Info(user_id) => {
                                 debug!("Info");
                                 let mut msg = "\nCurrently Playing:\n".to_owned();
                                 if playing {
-                                    let link = current_playing_link.clone().unwrap_or_default();
-                                    match read_info_json() {
+                                    let link = current_track.as_ref().map(|entry| entry.link.clone()).unwrap_or_default();
+                                    let info_path = playback_state.lock().await.info_path.clone().unwrap_or_default();
+                                    match read_info_json(&info_path) {
+                                        Ok(info_json) => {
+                                            msg += &format_track_info(&info_json.title, info_json.artist(), &link, config_json.bbcode);
+                                        }
+                                        Err(_) => match metadata_cache.lock().await.get(&link) {
+                                            Some(info_json) => {
+                                                msg += &format_track_info(&info_json.title, info_json.artist(), &link, config_json.bbcode);
+                                            }
+                                            None => msg += &format_link(&link, config_json.bbcode),
+                                        },
+                                    }
+                                } else {
+                                    msg += &"Nothing".to_owned();
+                                }
+                                send_ts_message(&mut init_con, reply_to(user_id), &msg);
+                            },
+                            Action::Ping(user_id) => {
+                                let msg = ping_message(connection_ping(&init_con), started.elapsed());
+                                send_ts_message(&mut init_con, reply_to(user_id), &msg);
+                            },
+                            Action::NowPlaying(user_id) => {
+                                debug!("Now playing");
+                                let msg = if playing {
+                                    let state = playback_state.lock().await;
+                                    let info_json = state.info_path.as_deref().and_then(|path| read_info_json(path).ok());
+                                    let (title, duration) = match &info_json {
+                                        Some(info_json) => (info_json.title.clone(), info_json.duration),
+                                        None => (state.link.clone().unwrap_or_default(), 0),
+                                    };
+                                    let progress = progress_bar(state.time_passed as u32, duration, state.paused);
+                                    format_now_playing(&title, &progress, config_json.bbcode)
+                                } else {
+                                    "Nothing is playing".to_string()
+                                };
+                                send_ts_message(&mut init_con, reply_to(user_id), &msg);
+                            },
+                            Action::ShowHistory(user_id) => {
+                                debug!("Show history");
+                                let msg = format_history(&history, HISTORY_LINES);
+                                send_ts_message(&mut init_con, reply_to(user_id), &msg);
+                            },
+                            Action::ShowQueue(user_id) => {
+                                debug!("Show queue");
+                                let mut msg = "\nCurrently Playing:\n".to_owned();
+                                if playing {
+                                    let link = current_track.as_ref().map(|entry| entry.link.clone()).unwrap_or_default();
+                                    let info_path = playback_state.lock().await.info_path.clone().unwrap_or_default();
+                                    match read_info_json(&info_path) {
                                         Ok(info_json) => {
-                                            msg += &format!("Title: {}\nChannel: {}\nLink: {}", info_json.title, info_json.channel, link);
+                                            msg += &format!("{} ({})", info_json.title, link);
                                         }
                                         Err(_) => {
-                                            msg += &format!("{}", link);
+                                            msg += &link;
                                         }
                                     }
                                 } else {
-                                    msg += &"Nothing".to_owned();
+                                    msg += "Nothing";
                                 }
-                                send_ts_message(&mut init_con, MessageTarget::Client(user_id), &msg);
+                                msg += "\n\nQueue:\n";
+                                if play_queue.is_empty() {
+                                    msg += "Queue is empty";
+                                } else {
+                                    let cache = metadata_cache.lock().await;
+                                    for (i, entry) in play_queue.iter().enumerate() {
+                                        let title = cache.title(&entry.link).unwrap_or(&entry.link);
+                                        msg += &format!("{}. {} (requested by {})\n", i + 1, title, entry.requester);
+                                    }
+                                }
+                                send_ts_message(&mut init_con, reply_to(user_id), &msg);
+                            },
+                            Action::SkipTo(index, user_id) => {
+                                debug!("Skip to");
+                                // A crossfading or prefetched track was taken from the front of the queue
+                                let upcoming = play_queue.len() + crossfade_track.is_some() as usize;
+                                let valid = playing && index > 0 && index <= upcoming;
+                                let msg = if !playing {
+                                    "Nothing is playing".to_string()
+                                } else if upcoming == 0 {
+                                    "Queue is empty".to_string()
+                                } else if !valid {
+                                    format!("Invalid index: {} (queue has {} entries)", index, upcoming)
+                                } else {
+                                    if let Some(next) = crossfade_track.take() {
+                                        play_queue.push_front(next);
+                                    }
+                                    let skipped: Vec<QueueEntry> = play_queue.drain(..index - 1).collect();
+                                    if repeat_mode == RepeatMode::All {
+                                        play_queue.extend(skipped);
+                                    }
+                                    metrics.skips.fetch_add(1, Ordering::Relaxed);
+                                    paused = false;
+                                    skip_requested = true;
+                                    let _ = cmd_send.send(PlayTaskCmd::Stop { fade_out: true }).await;
+                                    match index {
+                                        1 => "Skipped 1 track".to_string(),
+                                        _ => format!("Skipped {} tracks", index),
+                                    }
+                                };
+                                send_confirmation(&mut init_con, reply_to(user_id), &msg, quiet && valid);
+                            },
+                            Action::RemoveFromQueue(index, user_id) => {
+                                debug!("Remove from queue");
+                                let valid = index > 0 && index <= play_queue.len();
+                                let msg = if play_queue.is_empty() {
+                                    "Queue is empty".to_string()
+                                } else if index == 0 || index > play_queue.len() {
+                                    format!("Invalid index: {} (queue has {} entries)", index, play_queue.len())
+                                } else {
+                                    match play_queue.remove(index - 1) {
+                                        Some(entry) => format!("Removed {}. {}", index, entry.link),
+                                        None => format!("Invalid index: {}", index),
+                                    }
+                                };
+                                send_confirmation(&mut init_con, reply_to(user_id), &msg, quiet && valid);
+                            },
+                            Action::MoveQueueItem(from, to, user_id) => {
+                                debug!("Move queue item");
+                                let len = play_queue.len();
+                                let valid = from > 0 && from <= len && to > 0 && to <= len;
+                                let msg = if play_queue.is_empty() {
+                                    "Queue is empty".to_string()
+                                } else if from == 0 || from > len || to == 0 || to > len {
+                                    format!("Invalid index: positions must be between 1 and {}", len)
+                                } else {
+                                    let entry = play_queue.remove(from - 1).unwrap();
+                                    let msg = format!("Moved {} to position {}", entry.link, to);
+                                    play_queue.insert(to - 1, entry);
+                                    msg
+                                };
+                                send_confirmation(&mut init_con, reply_to(user_id), &msg, quiet && valid);
+                            },
+                            Action::LoadPlaylist(name, user_id) => {
+                                debug!("Load playlist");
+                                match load_playlist(&name) {
+                                    Ok(links) if links.is_empty() => send_ts_message(&mut init_con, reply_to(user_id), "Playlist is empty"),
+                                    Ok(links) => {
+                                        if let Err(e) = status_send.send(Action::EnqueuePlaylist(links, user_id)).await {
+                                            error!("Status packet sending error: {}", e);
+                                        }
+                                    }
+                                    Err(e) => {
+                                        let msg = format!("Could not load playlist {}: {}", name, e);
+                                        send_ts_message(&mut init_con, reply_to(user_id), &msg);
+                                    }
+                                }
+                            },
+                            Action::SavePlaylist(name, user_id) => {
+                                debug!("Save playlist");
+                                let links: Vec<&str> = current_track.iter().chain(play_queue.iter()).map(|entry| entry.link.as_str()).collect();
+                                let saved = save_playlist(&name, &links);
+                                let msg = match &saved {
+                                    Ok(()) => format!("Saved {} tracks to playlist {}", links.len(), name),
+                                    Err(e) => format!("Could not save playlist {}: {}", name, e),
+                                };
+                                send_confirmation(&mut init_con, reply_to(user_id), &msg, quiet && saved.is_ok());
+                            },
+                            Action::ClearQueue(user_id) => {
+                                debug!("Clear queue");
+                                let removed = play_queue.len();
+                                play_queue.clear();
+                                let msg = format!("Removed {} tracks from the queue", removed);
+                                send_confirmation(&mut init_con, reply_to(user_id), &msg, quiet);
+                            },
+                            Action::SetRepeatMode(mode, user_id) => {
+                                debug!("Set repeat mode");
+                                let msg = match mode {
+                                    Some(mode) => {
+                                        repeat_mode = mode;
+                                        let mut state = playback_state.lock().await;
+                                        state.repeat = mode;
+                                        state.changed();
+                                        drop(state);
+                                        format!("Repeat mode set to: {}", mode.as_str())
+                                    }
+                                    None => format!("Current repeat mode: {}", repeat_mode.as_str()),
+                                };
+                                send_confirmation(&mut init_con, reply_to(user_id), &msg, quiet && mode.is_some());
+                            },
+                            Action::SetEq(preset, user_id) => {
+                                debug!("Set equalizer");
+                                let msg = match preset {
+                                    Some(preset) => {
+                                        let mut state = playback_state.lock().await;
+                                        state.eq = preset;
+                                        state.changed();
+                                        let position = state.time_passed as u32;
+                                        drop(state);
+                                        // Restart the pipeline at the current position so the preset applies right away
+                                        if playing {
+                                            let _ = cmd_send.send(PlayTaskCmd::Seek { position }).await;
+                                        }
+                                        format!("Equalizer set to: {}", preset.as_str())
+                                    }
+                                    None => format!("Current equalizer: {}", playback_state.lock().await.eq.as_str()),
+                                };
+                                send_confirmation(&mut init_con, reply_to(user_id), &msg, quiet && preset.is_some());
+                            },
+                            Action::SetSpeed(speed, user_id) => {
+                                debug!("Set speed");
+                                let msg = match speed {
+                                    Some(speed) if !(MIN_SPEED..=MAX_SPEED).contains(&speed) => {
+                                        format!("Speed must be between {} and {}", MIN_SPEED, MAX_SPEED)
+                                    }
+                                    Some(speed) => {
+                                        let mut state = playback_state.lock().await;
+                                        state.speed = speed;
+                                        state.changed();
+                                        let position = state.time_passed as u32;
+                                        drop(state);
+                                        // Like the equalizer the speed only applies to a restarted pipeline
+                                        if playing {
+                                            let _ = cmd_send.send(PlayTaskCmd::Seek { position }).await;
+                                        }
+                                        format!("Speed set to: {}x", speed)
+                                    }
+                                    None => format!("Current speed: {}x", playback_state.lock().await.speed),
+                                };
+                                let changed = speed.is_some_and(|speed| (MIN_SPEED..=MAX_SPEED).contains(&speed));
+                                send_confirmation(&mut init_con, reply_to(user_id), &msg, quiet && changed);
+                            },
+                            Action::Shuffle(user_id) => {
+                                debug!("Shuffle");
+                                play_queue.make_contiguous().shuffle(&mut rand::thread_rng());
+                                let msg = format!("Shuffled {} tracks", play_queue.len());
+                                send_confirmation(&mut init_con, reply_to(user_id), &msg, quiet);
+                            },
+                            Action::SetShuffle(enabled, user_id) => {
+                                debug!("Set shuffle");
+                                shuffle = enabled;
+                                let msg = if shuffle {
+                                    play_queue.make_contiguous().shuffle(&mut rand::thread_rng());
+                                    format!("Shuffle enabled, shuffled {} tracks", play_queue.len())
+                                } else {
+                                    "Shuffle disabled".to_string()
+                                };
+                                send_confirmation(&mut init_con, reply_to(user_id), &msg, quiet);
+                            },
+                            Action::Seek(target, user_id) => {
+                                debug!("Seek");
+                                let mut routine = false;
+                                let msg = if playing {
+                                    let state = playback_state.lock().await;
+                                    let current = state.time_passed as u32;
+                                    let info_path = state.info_path.clone().unwrap_or_default();
+                                    drop(state);
+                                    let duration = read_info_json(&info_path).map(|info_json| info_json.duration).unwrap_or(0);
+                                    let position = match target {
+                                        SeekPosition::Absolute(secs) => secs,
+                                        SeekPosition::Forward(secs) => current.saturating_add(secs),
+                                        SeekPosition::Backward(secs) => current.saturating_sub(secs),
+                                    };
+                                    if duration > 0 && position >= duration {
+                                        format!("Cannot seek to {}, the track is only {} long", format_duration(position), format_duration(duration))
+                                    } else {
+                                        let _ = cmd_send.send(PlayTaskCmd::Seek { position }).await;
+                                        routine = true;
+                                        format!("Seeking to {}", format_duration(position))
+                                    }
+                                } else {
+                                    "Nothing is playing".to_string()
+                                };
+                                send_confirmation(&mut init_con, reply_to(user_id), &msg, quiet && routine);
+                            },
+                            Action::Replay(user_id) => {
+                                debug!("Replay");
+                                let msg = if playing {
+                                    let position = current_track.as_ref().and_then(|track| track.start).unwrap_or(0);
+                                    let _ = cmd_send.send(PlayTaskCmd::Seek { position }).await;
+                                    "Restarting the current track".to_string()
+                                } else {
+                                    "Nothing is playing".to_string()
+                                };
+                                send_confirmation(&mut init_con, reply_to(user_id), &msg, quiet && playing);
+                            },
+                            Action::PlayPrevious(user_id) => {
+                                debug!("Play previous");
+                                let found = !history.is_empty();
+                                let msg = match history.pop_front() {
+                                    None => "No previous track".to_string(),
+                                    Some(HistoryEntry { entry, .. }) => {
+                                        let msg = format!("Playing previous track: {}", entry.link);
+                                        if playing {
+                                            if let Some(next) = crossfade_track.take() {
+                                                play_queue.push_front(next);
+                                            }
+                                            // Take the current track so the finished track isn't added to the history again
+                                            if let Some(current) = current_track.take() {
+                                                play_queue.push_front(current);
+                                            }
+                                            play_queue.push_front(entry);
+                                            paused = false;
+                                            let _ = cmd_send.send(PlayTaskCmd::Stop { fade_out: true }).await;
+                                        } else {
+                                            playing = true;
+                                            paused = false;
+                                            cmd_send = start_playback(&entry, &pkt_send, volume, &playback_state, &config_json, 0, None);
+                                            current_track = Some(entry);
+                                            skip_votes.clear();
+                                        }
+                                        msg
+                                    }
+                                };
+                                send_confirmation(&mut init_con, reply_to(user_id), &msg, quiet && found);
                             },
                             Action::Help(user_id) => {
                                 debug!("Help");
-                                let msg = "\nCommands:\n!play <link> or !yt <link> - Play audio from link or queue if already playing\n!next <link> or !n <link> - Queue a track as the next track\n!pause or !p - Pause current track\n!resume, !r, !continue, or !c - Resume current track\n!skip, !s, !next, or !n - Skip current track\n!stop - Stop all tracks\n!volume <modifier> or !v <modifier> - Change volume (modifier should be a number from 0 to 100)\n!info or !i - Get info about current track\n!help or !h - Get this message\n!quit or !q - Quit\n".to_owned();
-                                send_ts_message(&mut init_con, MessageTarget::Client(user_id), &msg);
+                                let msg = "\nCommands:\n!play <link|search> or !yt <link|search> - Play audio from link or search term or queue if already playing\n!playlocal <path> - Play or queue a file from the music directory\n!search <query> - List the top search results\n!pick <n> - Play or queue result n of your last search\n!next <link> or !n <link> - Queue a track as the next track\n!pause or !p - Pause current track\n!resume, !r, !continue, or !c - Resume current track\n!toggle or !t - Pause or resume current track\n!mute or !stfu, !unmute - Stop or resume sending audio while the track keeps playing\n!quiet - Turn confirmations of commands off or on\n!skip, !s, !next, or !n - Skip current track\n!voteskip - Vote to skip the current track\n!skipto <n> - Skip to track n of the queue\n!prev - Play the previous track again\n!replay - Restart the current track from the beginning\n!join - Move the bot into your channel\n!leave - Pause and move the bot to the idle channel\n!stop - Stop all tracks\n!clear - Clear the queue without stopping the current track\n!seek <seconds>, !seek +<seconds>, or !seek -<seconds> - Jump to a position in the current track\n!volume <modifier> or !v <modifier> - Change volume (modifier should be a number from 0 to 100)\n!volup or !vu, !voldown or !vd - Turn the volume up or down a step\n!info or !i - Get info about current track\n!np - Show the progress of the current track\n!ping - Show the ping to the server and the uptime of the bot\n!queue or !ql - List the queued tracks\n!history - List the last played tracks\n!loop <off|one|all> - Repeat the current track or the whole queue\n!eq <flat|bass|treble|vocal> - Change the equalizer preset\n!speed <0.5-2> - Change the playback speed\n!shuffle [on|off] - Shuffle the queue once or toggle reshuffling after every track\n!remove <n> or !rm <n> - Remove the track at position n from the queue\n!move <from> <to> - Move a track to another position in the queue\n!load <name> - Queue all tracks of a saved playlist\n!save <name> - Save the current track and the queue as a playlist\n!dj, !dj off - Take or give up exclusive control of playback\n!name <name> - Change the nickname of the bot\n!help or !h - Get this message\n!restart - Reconnect to the server\n!quit or !q - Quit\n".replace('!', &config_json.prefix);
+                                send_ts_message(&mut init_con, reply_to(user_id), &msg);
+                            },
+                            Action::SetMute(mute, user_id) => {
+                                debug!("Set mute");
+                                muted = mute;
+                                let mut state = playback_state.lock().await;
+                                state.muted = muted;
+                                state.changed();
+                                drop(state);
+                                let msg = if muted { "Muted, the track keeps playing silently" } else { "Unmuted" };
+                                send_confirmation(&mut init_con, reply_to(user_id), msg, quiet);
+                            },
+                            Action::ToggleQuiet(user_id) => {
+                                quiet = !quiet;
+                                let msg = if quiet { "Quiet mode on, only errors and requested information are sent" } else { "Quiet mode off" };
+                                send_ts_message(&mut init_con, reply_to(user_id), msg);
+                            },
+                            Action::Rename(name, user_id) => match check_name(&name) {
+                                Err(e) => send_ts_message(&mut init_con, reply_to(user_id), &e),
+                                Ok(()) => {
+                                    set_ts_name(&mut init_con, &name);
+                                    send_confirmation(&mut init_con, reply_to(user_id), &format!("Renamed to {}", name), quiet);
+                                    ts_name = name;
+                                }
+                            },
+                            Action::Join(user_id) => {
+                                debug!("Join");
+                                follow_client(&mut init_con, user_id);
+                                send_confirmation(&mut init_con, reply_to(user_id), "Joining your channel", quiet);
+                            },
+                            Action::Leave(user_id) => {
+                                debug!("Leave");
+                                if playing && !paused {
+                                    paused = true;
+                                    let _ = cmd_send.send(PlayTaskCmd::Pause).await;
+                                }
+                                match config_json.idle_channel {
+                                    Some(channel) => {
+                                        // Reply before moving, the requester may not see the bot afterwards
+                                        send_confirmation(&mut init_con, reply_to(user_id), "Leaving, playback is paused", quiet);
+                                        move_to_channel(&mut init_con, ChannelId(channel));
+                                    }
+                                    None => send_ts_message(&mut init_con, reply_to(user_id), "Playback is paused, no idle_channel is configured to move to"),
+                                }
+                            },
+                            Action::ListenersChanged => {
+                                // Wait for the channel to settle before pausing or resuming
+                                listeners_check = Some(Instant::now() + AUTO_PAUSE_DELAY);
+                            },
+                            Action::NotPermitted(user_id) => {
+                                send_ts_message(&mut init_con, reply_to(user_id), "You are not permitted to use this command");
+                            },
+                            Action::PrivateOnly(user_id) => {
+                                send_ts_message(&mut init_con, MessageTarget::Client(user_id), "Send this command to the bot in a private message");
+                            },
+                            Action::SetDj(on, user_id) => {
+                                let admin = is_admin(client_uid(&init_con, user_id).as_deref(), &config_json.admins);
+                                let mut dj = dj.lock().await;
+                                let msg = match (on, *dj) {
+                                    (true, Some(current)) if current == user_id => "You are the DJ already",
+                                    (true, Some(_)) if !admin => "Someone else is the DJ",
+                                    (true, _) => {
+                                        *dj = Some(user_id);
+                                        "You are the DJ now, only you can control playback"
+                                    }
+                                    (false, Some(current)) if current == user_id || admin => {
+                                        *dj = None;
+                                        "DJ mode is off, everyone can control playback again"
+                                    }
+                                    (false, Some(_)) => "Only the DJ or an admin can end DJ mode",
+                                    (false, None) => "Nobody is the DJ",
+                                };
+                                send_ts_message(&mut init_con, reply_to(user_id), msg);
+                            },
+                            Action::Usage(usage, user_id) => {
+                                let msg = format!("Usage: {}", usage.replace('!', &config_json.prefix));
+                                send_ts_message(&mut init_con, reply_to(user_id), &msg);
+                            },
+                            Action::NotDj(user_id) => {
+                                send_ts_message(&mut init_con, reply_to(user_id), "Only the DJ can control playback right now");
+                            },
+                            Action::RateLimited(user_id) => {
+                                send_ts_message(&mut init_con, reply_to(user_id), "Slow down, you are sending commands too fast");
+                            },
+                            Action::Restart(user_id) => {
+                                info!("Restarting the TeamSpeak connection");
+                                send_confirmation(&mut init_con, reply_to(user_id), "Reconnecting to the server", quiet);
+                                if let Err(e) = init_con.disconnect(DisconnectOptions::new()) {
+                                    warn!("Failed to disconnect: {}", e);
+                                }
+                                let _ = timeout(SHUTDOWN_TIMEOUT, init_con.events().for_each(|_| future::ready(()))).await;
+
+                                let mut status = connection_status.lock().await;
+                                status.connected = false;
+                                status.last_disconnect = Some(Utc::now().to_rfc3339());
+                                drop(status);
+
+                                init_con = reconnect(&config_json, &ts_name).await?;
+                                connection_status.lock().await.connected = true;
+                                send_ts_message(&mut init_con, MessageTarget::Channel, "Reconnected to the server");
                             },
                             Action::Quit => {
                                 debug!("Quit");
@@ -535,31 +2643,114 @@ async fn real_main() -> Result<()> {
                     None => {},
                     Some(msg) => {
                         if playing {
+                            // A handoff nobody waits for any more (stop, prev) just ends the track
+                            let msg = match msg {
+                                AudioPacket::Handoff(mut pipeline, _) if crossfade_track.is_none() => {
+                                    tokio::spawn(async move {
+                                        pipeline.stop(true).await;
+                                    });
+                                    AudioPacket::None
+                                }
+                                msg => msg,
+                            };
 
                             match msg {
+                                // Muting only drops the audio, the track keeps advancing
+                                AudioPacket::Payload(_) if muted => {},
                                 AudioPacket::Payload(pkt) => {
                                     if let Err(e) = init_con.send_audio(pkt) {
                                         error!("Audio packet sending error: {}", e);
                                         break;
                                     }
                                 },
+                                AudioPacket::NowPlaying(title) => {
+                                    metrics.tracks_played.fetch_add(1, Ordering::Relaxed);
+                                    set_ts_name(&mut init_con, &format!("♪ {}", title));
+                                    if let Some(track) = current_track.as_mut() {
+                                        track.title = Some(title.clone());
+                                    }
+                                    if config_json.announce {
+                                        let requester = if config_json.announce_requester {
+                                            // The name may have changed since the track was queued
+                                            current_track.as_ref().map(|track| {
+                                                track.requester_id
+                                                    .and_then(|id| client_name(&init_con, id))
+                                                    .unwrap_or_else(|| track.requester.clone())
+                                            })
+                                        } else {
+                                            None
+                                        };
+                                        // Replaces the announcement of a track skipped before it was sent
+                                        pending_announce.schedule(announcement(&title, requester.as_deref()), Instant::now() + ANNOUNCE_INTERVAL);
+                                    }
+                                },
+                                AudioPacket::Error(user_id, why) => {
+                                    metrics.errors.fetch_add(1, Ordering::Relaxed);
+                                    let msg = format!("Could not fetch that link: {}", why);
+                                    send_ts_message(&mut init_con, MessageTarget::Client(user_id), &msg);
+                                },
+                                AudioPacket::RequestNext => {
+                                    if crossfade_track.is_none() {
+                                        crossfade_track = if repeat_mode == RepeatMode::One {
+                                            current_track.clone()
+                                        } else {
+                                            if shuffle {
+                                                play_queue.make_contiguous().shuffle(&mut rand::thread_rng());
+                                            }
+                                            play_queue.pop_front()
+                                        };
+                                    }
+                                    if let Some(entry) = crossfade_track.as_ref() {
+                                        let _ = cmd_send.send(PlayTaskCmd::PrepareNext { link: entry.link.clone(), start_secs: entry.start.unwrap_or(0) }).await;
+                                    }
+                                },
+                                AudioPacket::Handoff(pipeline, played_secs) => {
+                                    if let Some(finished) = current_track.take() {
+                                        push_history(&mut history, finished.clone(), MAX_HISTORY);
+                                        if repeat_mode == RepeatMode::All {
+                                            play_queue.push_back(finished);
+                                        }
+                                    }
+                                    skip_requested = false;
+                                    if let Some(entry) = crossfade_track.take() {
+                                        cmd_send = start_playback(&entry, &pkt_send, volume, &playback_state, &config_json, entry.start.unwrap_or(0) + played_secs, Some(pipeline));
+                                        current_track = Some(entry);
+                                        skip_votes.clear();
+                                    }
+                                },
                                 AudioPacket::None => {
+                                    // The crossfade never took over, so the next track still has to be played
+                                    if let Some(entry) = crossfade_track.take() {
+                                        if repeat_mode != RepeatMode::One {
+                                            play_queue.push_front(entry);
+                                        }
+                                    }
+                                    if shuffle {
+                                        play_queue.make_contiguous().shuffle(&mut rand::thread_rng());
+                                    }
+                                    if let Some(entry) = current_track.take() {
+                                        push_history(&mut history, entry.clone(), MAX_HISTORY);
+                                        match repeat_mode {
+                                            RepeatMode::One if !skip_requested => play_queue.push_front(entry),
+                                            RepeatMode::All => play_queue.push_back(entry),
+                                            _ => {}
+                                        }
+                                    }
+                                    skip_requested = false;
                                     if play_queue.is_empty(){
                                         playing = false;
+                                        set_ts_name(&mut init_con, &ts_name);
+                                        pending_announce.clear();
+                                        if config_json.announce && !stop_requested {
+                                            send_ts_message(&mut init_con, MessageTarget::Channel, "Queue finished");
+                                        }
                                     } else {
-                                        let link = play_queue.pop_front().unwrap();
-                                        let audio_task_pkt_send = pkt_send.clone();
-
-                                        let (task_cmd_send,  task_cmd_recv) = mpsc::channel(4);
-
-                                        cmd_send = task_cmd_send;
-
-                                        current_playing_link = Some(link.clone());
-                                        let playback_state_clone = Arc::clone(&playback_state);
-                                        tokio::spawn(async move {
-                                            play_file(link, audio_task_pkt_send, task_cmd_recv, volume, playback_state_clone).await;
-                                        });
+                                        let entry = play_queue.pop_front().unwrap();
+                                        cmd_send = start_playback(&entry, &pkt_send, volume, &playback_state, &config_json, 0, None);
+                                        current_track = Some(entry);
+                                        skip_votes.clear();
                                     }
+                                    stop_requested = false;
                                 }
                             }
                         }
@@ -567,20 +2758,127 @@ async fn real_main() -> Result<()> {
                 }
             }
 
+            _ = sleep_until(pending_announce.deadline().unwrap_or_else(Instant::now)), if pending_announce.deadline().is_some() => {
+                if let Some(msg) = pending_announce.take_due(Instant::now()) {
+                    send_ts_message(&mut init_con, MessageTarget::Channel, &msg);
+                }
+            },
+            _ = sleep_until(listeners_check.unwrap_or_else(Instant::now)), if listeners_check.is_some() => {
+                listeners_check = None;
+                let listeners = count_listeners(&init_con).unwrap_or(1);
+                if listeners == 0 && playing && !paused {
+                    info!("Nobody is listening, pausing playback");
+                    paused = true;
+                    auto_paused = true;
+                    let _ = cmd_send.send(PlayTaskCmd::Pause).await;
+                } else if listeners > 0 && auto_paused {
+                    info!("Resuming playback for {} listeners", listeners);
+                    auto_paused = false;
+                    if playing && paused {
+                        paused = false;
+                        let _ = cmd_send.send(PlayTaskCmd::Resume).await;
+                    }
+                }
+            },
+            _ = sleep_until(last_activity + idle_timeout.unwrap_or_default()), if idle_timeout.is_some() && !playing && !idle => {
+                idle = true;
+                info!("Nothing played for {:?}, going idle", idle_timeout.unwrap_or_default());
+                if let Some(msg) = &config_json.idle_message {
+                    send_ts_message(&mut init_con, MessageTarget::Channel, msg);
+                }
+                match config_json.idle_action {
+                    IdleAction::Disconnect => break,
+                    IdleAction::Leave => match config_json.idle_channel {
+                        Some(channel) => move_to_channel(&mut init_con, ChannelId(channel)),
+                        None => warn!("No idle_channel configured to leave to"),
+                    },
+                }
+            },
             _ = tokio::signal::ctrl_c() => {
                 info!("Received Ctrl+C signal, shutting down...");
                 break;
             },
-            _ = sigterm.recv() => {
+            _ = sigterm.notified() => {
                 info!("Received SIGTERM signal, shutting down...");
                 break;
             },
             r = events => {
-                r?;
-                init_con.disconnect(DisconnectOptions::new())?;
-                bail!("Disconnected");
+                match r {
+                    Err(e) => error!("TeamSpeak connection error: {}", e),
+                    Ok(()) => warn!("Disconnected from TeamSpeak Server"),
+                }
+                let mut status = connection_status.lock().await;
+                status.connected = false;
+                status.last_disconnect = Some(Utc::now().to_rfc3339());
+                drop(status);
+
+                init_con = reconnect(&config_json, &ts_name).await?;
+                connection_status.lock().await.connected = true;
             }
         };
+
+        // The idle timeout only starts counting once playback ends
+        if playing {
+            last_activity = Instant::now();
+        }
+
+        if saved_current != current_track || saved_queue != play_queue {
+            let position = if saved_current == current_track {
+                playback_state.lock().await.time_passed
+            } else {
+                0.0
+            };
+            saved_current = current_track.clone();
+            saved_queue = play_queue.clone();
+            *queue_snapshot.lock().await = play_queue.clone();
+            // The status JSON includes the queue length and the next track
+            playback_state.lock().await.changed();
+            let cache = metadata_cache.lock().await;
+            let unresolved = play_queue.iter().filter(|entry| {
+                !entry.link.starts_with(LOCAL_PREFIX) && cache.get(&entry.link).is_none()
+            });
+            for entry in unresolved {
+                // The fetcher is busy, the rest is picked up on the next queue change
+                if metadata_send.try_send(entry.link.clone()).is_err() {
+                    break;
+                }
+            }
+            drop(cache);
+            write_queue_state(
+                QUEUE_STATE_FILE,
+                &QueueState {
+                    current: saved_current.clone(),
+                    position,
+                    queue: saved_queue.clone(),
+                    history: history.clone(),
+                },
+            );
+        }
+    }
+
+    // Remember the playback position so the next start can resume mid-track
+    let position = playback_state.lock().await.time_passed;
+    write_queue_state(
+        QUEUE_STATE_FILE,
+        &QueueState {
+            current: current_track.clone(),
+            position,
+            queue: play_queue.clone(),
+            history: history.clone(),
+        },
+    );
+
+    // Let the play task kill yt-dlp and ffmpeg before the runtime shuts down
+    if playing {
+        let _ = cmd_send.send(PlayTaskCmd::Stop { fade_out: false }).await;
+        let _ = timeout(SHUTDOWN_TIMEOUT, async {
+            while let Some(packet) = pkt_recv.recv().await {
+                if let AudioPacket::None | AudioPacket::Handoff(..) = packet {
+                    break;
+                }
+            }
+        })
+        .await;
     }
 
     // Disconnect
@@ -0,0 +1,97 @@
+use log::error;
+use std::fs;
+use std::io::{self, ErrorKind};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub async fn cleanup_process(
+    process: &mut tokio::process::Child,
+    name: &str,
+    temp_file: Option<&str>,
+) -> () {
+    if let Err(e) = process.kill().await {
+        error!("Failed to kill {}: {}", name, e);
+    }
+    match process.wait().await {
+        Ok(status) => {
+            if !status.success() && !status.code().is_none() {
+                error!("{} exited with non-zero status: {:?}", name, status.code());
+            }
+        }
+        Err(e) => error!("Failed to wait on {}: {}", name, e),
+    }
+
+    if let Some(path) = temp_file {
+        if let Err(e) = fs::remove_file(path) {
+            if e.kind() != ErrorKind::NotFound {
+                error!("Failed to remove {}: {}", path, e);
+            }
+        }
+    }
+}
+
+/// Fills `buf` like `read_exact`, but returns how many bytes were read if the stream ends early.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]).await? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+/// A single `atempo` only accepts factors from 0.5 to 2, anything beyond is chained.
+pub fn atempo_filter(speed: f32) -> String {
+    let mut speed = speed;
+    let mut factors = Vec::new();
+    while speed > 2.0 {
+        factors.push(2.0);
+        speed /= 2.0;
+    }
+    while speed < 0.5 {
+        factors.push(0.5);
+        speed /= 0.5;
+    }
+    factors.push(speed);
+
+    factors
+        .iter()
+        .map(|factor| format!("atempo={}", factor))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// Maps the user facing volume (0 to 1) onto a logarithmic curve spanning `VOLUME_RANGE_DB`,
+/// so every step of `!volume` changes the perceived loudness by roughly the same amount.
+pub fn volume_to_gain(volume: f32) -> f32 {
+    const VOLUME_RANGE_DB: f32 = 50.0;
+
+    if volume <= 0.0 {
+        return 0.0;
+    }
+    10_f32.powf((volume.min(1.0) - 1.0) * VOLUME_RANGE_DB / 20.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn partial_frames() {
+        let mut buf = [0u8; 8];
+        let mut full: &[u8] = &[1; 12];
+        assert_eq!(block_on(read_frame(&mut full, &mut buf)).unwrap(), 8);
+        assert_eq!(block_on(read_frame(&mut full, &mut buf)).unwrap(), 4);
+        assert_eq!(buf[..4], [1; 4]);
+        assert_eq!(block_on(read_frame(&mut full, &mut buf)).unwrap(), 0);
+    }
+
+    #[test]
+    fn atempo_chaining() {
+        assert_eq!(atempo_filter(1.5), "atempo=1.5");
+        assert_eq!(atempo_filter(3.0), "atempo=2,atempo=1.5");
+        assert_eq!(atempo_filter(0.25), "atempo=0.5,atempo=0.5");
+    }
+}
@@ -0,0 +1,415 @@
+use crate::{Config, IpVersion, HTTP_CLIENT_ID};
+use anyhow::{anyhow, Context, Result};
+use futures::prelude::*;
+use log::{error, info, warn};
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::lookup_host;
+use tokio::time::{Duration, Instant};
+use tsclientlib::{
+    ChannelId, ClientId, Connection, Identity, MessageTarget, OutCommandExt, StreamItem,
+};
+
+pub fn connect_to_ts(config: Config) -> Result<Connection> {
+    let con_config = Connection::build(config.host)
+        .name(config.name)
+        .password(config.password)
+        .log_commands(false)
+        .log_packets(false)
+        .log_udp_packets(false);
+
+    let id = Identity::new_from_str(&config.id)
+        .map_err(|why| anyhow!("Invalid teamspeak3 identity string: {}", why))?;
+
+    let con_config = con_config.identity(id);
+
+    con_config
+        .connect()
+        .map_err(|why| anyhow!("Unable to connect: {}", why))
+}
+
+pub async fn wait_for_connection(con: &mut Connection) -> Result<()> {
+    let r = con
+        .events()
+        .try_filter(|e| future::ready(matches!(e, StreamItem::BookEvents(_))))
+        .next()
+        .await;
+    if let Some(r) = r {
+        r?;
+    }
+
+    Ok(())
+}
+
+pub fn send_ts_message(con: &mut Connection, target: MessageTarget, msg: &str) -> () {
+    if let MessageTarget::Client(HTTP_CLIENT_ID) = target {
+        return;
+    }
+
+    let state = con.get_state().unwrap_or_else(|e| {
+        panic!("Unable to get state: {}", e);
+    });
+
+    if let Err(e) = state.send_message(target, &msg).send_with_result(con) {
+        error!("Message sending error: {}", e);
+    }
+}
+
+pub fn announcement(title: &str, requester: Option<&str>) -> String {
+    match requester {
+        Some(requester) => format!("Now playing: {} (requested by {})", title, requester),
+        None => format!("Now playing: {}", title),
+    }
+}
+
+/// Holds back the announcement of a track until it played for a moment, a track skipped
+/// before that is replaced by the next one without being announced.
+#[derive(Debug, Default)]
+pub struct PendingAnnouncement {
+    pending: Option<(Instant, String)>,
+}
+
+impl PendingAnnouncement {
+    pub fn schedule(&mut self, msg: String, at: Instant) {
+        self.pending = Some((at, msg));
+    }
+
+    pub fn clear(&mut self) {
+        self.pending = None;
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.as_ref().map(|(at, _)| *at)
+    }
+
+    /// Returns the announcement once its time has come.
+    pub fn take_due(&mut self, now: Instant) -> Option<String> {
+        match &self.pending {
+            Some((at, _)) if *at <= now => self.pending.take().map(|(_, msg)| msg),
+            _ => None,
+        }
+    }
+}
+
+/// Confirms a routine command, left out in quiet mode.
+pub fn send_confirmation(con: &mut Connection, target: MessageTarget, msg: &str, quiet: bool) {
+    if !quiet {
+        send_ts_message(con, target, msg);
+    }
+}
+
+pub fn client_uid(con: &Connection, user_id: ClientId) -> Option<String> {
+    let state = con.get_state().ok()?;
+    let client = state.clients.get(&user_id)?;
+    client.uid.as_ref().map(|uid| uid.as_ref().to_string())
+}
+
+/// Display name of a client from the connection book.
+pub fn client_name(con: &Connection, user_id: ClientId) -> Option<String> {
+    con.get_state()
+        .ok()
+        .and_then(|state| state.clients.get(&user_id))
+        .map(|client| client.name.clone())
+}
+
+/// Moves the bot into the channel of the given client, failures are only logged.
+pub fn follow_client(con: &mut Connection, user_id: ClientId) {
+    if user_id == HTTP_CLIENT_ID {
+        return;
+    }
+
+    let state = match con.get_state() {
+        Ok(state) => state,
+        Err(e) => {
+            error!("Unable to get state: {}", e);
+            return;
+        }
+    };
+
+    match state.clients.get(&user_id) {
+        Some(client) => {
+            let channel = client.channel;
+            move_to_channel(con, channel);
+        }
+        None => warn!("Unable to find client {} to follow", user_id),
+    }
+}
+
+/// Moves the bot into the given channel unless it already is there, failures are only logged.
+pub fn move_to_channel(con: &mut Connection, channel: ChannelId) {
+    let state = match con.get_state() {
+        Ok(state) => state,
+        Err(e) => {
+            error!("Unable to get state: {}", e);
+            return;
+        }
+    };
+
+    let own_client = match state.clients.get(&state.own_client) {
+        Some(client) if client.channel != channel => client,
+        _ => return,
+    };
+
+    if let Err(e) = own_client.set_channel(channel).send_with_result(con) {
+        warn!("Unable to move to channel {:?}: {}", channel, e);
+    }
+}
+
+/// Number of other clients in the channel of the bot, `None` if the book is unavailable.
+pub fn count_listeners(con: &Connection) -> Option<usize> {
+    let state = con.get_state().ok()?;
+    let own_channel = state.clients.get(&state.own_client)?.channel;
+
+    Some(
+        state
+            .clients
+            .iter()
+            .filter(|(id, client)| **id != state.own_client && client.channel == own_channel)
+            .count(),
+    )
+}
+
+/// Ping of the bot as reported by the server, only known once it sent the connection info.
+pub fn connection_ping(con: &Connection) -> Option<Duration> {
+    let state = con.get_state().ok()?;
+    let own_client = state.clients.get(&state.own_client)?;
+    own_client.connection_data.as_ref()?.ping
+}
+
+pub fn ping_message(ping: Option<Duration>, uptime: Duration) -> String {
+    let ping = ping.map_or_else(
+        || "n/a".to_string(),
+        |ping| format!("{} ms", ping.as_millis()),
+    );
+    let secs = uptime.as_secs();
+    format!(
+        "Ping: {}, uptime: {}d {}h {}m",
+        ping,
+        secs / 86400,
+        secs / 3600 % 24,
+        secs / 60 % 60
+    )
+}
+
+/// TeamSpeak rejects nicknames outside of these lengths.
+const MIN_NAME_LEN: usize = 3;
+const MAX_NAME_LEN: usize = 30;
+
+/// Checks a nickname given with `!name`, longer names would be cut off by `set_ts_name`.
+pub fn check_name(name: &str) -> Result<(), String> {
+    let len = name.chars().count();
+    if len < MIN_NAME_LEN || len > MAX_NAME_LEN {
+        return Err(format!(
+            "The name must be {} to {} characters long",
+            MIN_NAME_LEN, MAX_NAME_LEN
+        ));
+    }
+    if name.trim() != name || name.chars().any(|c| c.is_control() || c == '\\') {
+        return Err(
+            "The name must not start or end with spaces or contain control characters or \\"
+                .to_string(),
+        );
+    }
+    Ok(())
+}
+
+/// Changes the nickname of the bot, cut down to the 30 characters TeamSpeak allows.
+pub fn set_ts_name(con: &mut Connection, name: &str) {
+    let name: String = if name.chars().count() > MAX_NAME_LEN {
+        name.chars()
+            .take(MAX_NAME_LEN - 1)
+            .chain(std::iter::once('…'))
+            .collect()
+    } else {
+        name.to_string()
+    };
+
+    let state = match con.get_state() {
+        Ok(state) => state,
+        Err(e) => {
+            error!("Unable to get state: {}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = state.client_update().set_name(&name).send_with_result(con) {
+        error!("Nickname update error: {}", e);
+    }
+}
+
+/// Replaces the hostname in `host` by an address of the preferred version and keeps an explicit
+/// port. This skips the SRV and TSDNS lookups of tsclientlib, so it is only done with
+/// `resolve_host` set.
+pub async fn resolve_host(host: &str, version: IpVersion) -> Result<String> {
+    let (name, port) = match split_hostname(host) {
+        Some(parts) => parts,
+        None => return Ok(host.to_string()),
+    };
+    match lookup_ip(name, version).await {
+        Ok(ip) => {
+            info!("Resolved host: {} to IP: {}", name, ip);
+            Ok(connect_address(ip, port))
+        }
+        Err(e) => {
+            error!("{:#}. Using hostname as fallback.", e);
+            Ok(host.to_string())
+        }
+    }
+}
+
+/// Unlike `resolve_host` this fails instead of falling back to the hostname.
+pub async fn lookup_ip(host: &str, version: IpVersion) -> Result<IpAddr> {
+    let addresses = lookup_host((host, 0))
+        .await
+        .with_context(|| format!("DNS resolution error for host: {}", host))?;
+    select_address(addresses, version)
+        .ok_or_else(|| anyhow!("No {} address found for host: {}", version.as_str(), host))
+}
+
+/// `auto` takes the first address in the order of the system resolver.
+fn select_address(
+    addresses: impl IntoIterator<Item = SocketAddr>,
+    version: IpVersion,
+) -> Option<IpAddr> {
+    addresses
+        .into_iter()
+        .map(|addr| addr.ip())
+        .find(|ip| match version {
+            IpVersion::Ipv4 => ip.is_ipv4(),
+            IpVersion::Ipv6 => ip.is_ipv6(),
+            IpVersion::Auto => true,
+        })
+}
+
+/// IPv6 addresses are bracketed so a port can follow them.
+fn connect_address(ip: IpAddr, port: Option<&str>) -> String {
+    let ip = match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{}]", ip),
+    };
+    match port {
+        Some(port) => format!("{}:{}", ip, port),
+        None => ip,
+    }
+}
+
+/// Splits the hostname from an optional `:port`, `None` for IP addresses which need no resolving.
+pub fn split_hostname(host: &str) -> Option<(&str, Option<&str>)> {
+    if host.parse::<IpAddr>().is_ok() || host.starts_with('[') {
+        return None;
+    }
+    let (name, port) = match host.rsplit_once(':') {
+        Some((name, port)) if port.parse::<u16>().is_ok() => (name, Some(port)),
+        _ => (host, None),
+    };
+    if name.is_empty() || name.parse::<IpAddr>().is_ok() {
+        return None;
+    }
+    Some((name, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ping_message_format() {
+        assert_eq!(
+            ping_message(Some(Duration::from_millis(42)), Duration::from_secs(90_061)),
+            "Ping: 42 ms, uptime: 1d 1h 1m"
+        );
+        assert_eq!(
+            ping_message(None, Duration::from_secs(59)),
+            "Ping: n/a, uptime: 0d 0h 0m"
+        );
+    }
+
+    #[test]
+    fn nicknames() {
+        assert!(check_name("DJ Bot - Friday Mix").is_ok());
+        assert!(check_name("abc").is_ok());
+        assert!(check_name("ab").is_err());
+        assert!(check_name(&"a".repeat(31)).is_err());
+        assert!(check_name(" Bot").is_err());
+        assert!(check_name("Bot\tname").is_err());
+        assert!(check_name("Bot\\name").is_err());
+    }
+
+    #[test]
+    fn address_selection() {
+        let v6: SocketAddr = "[2001:db8::1]:0".parse().unwrap();
+        let v4: SocketAddr = "192.0.2.1:0".parse().unwrap();
+        let other_v4: SocketAddr = "192.0.2.2:0".parse().unwrap();
+        assert_eq!(
+            select_address(vec![v6, v4, other_v4], IpVersion::Ipv4),
+            Some("192.0.2.1".parse().unwrap())
+        );
+        assert_eq!(select_address(vec![v6], IpVersion::Ipv4), None);
+        assert_eq!(select_address(Vec::new(), IpVersion::Ipv4), None);
+        assert_eq!(
+            select_address(vec![v4, v6], IpVersion::Ipv6),
+            Some("2001:db8::1".parse().unwrap())
+        );
+        assert_eq!(select_address(vec![v4], IpVersion::Ipv6), None);
+        assert_eq!(
+            select_address(vec![v6, v4], IpVersion::Auto),
+            Some("2001:db8::1".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn connect_addresses() {
+        let v4: IpAddr = "192.0.2.1".parse().unwrap();
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(connect_address(v4, None), "192.0.2.1");
+        assert_eq!(connect_address(v4, Some("9987")), "192.0.2.1:9987");
+        assert_eq!(connect_address(v6, None), "[2001:db8::1]");
+        assert_eq!(connect_address(v6, Some("9987")), "[2001:db8::1]:9987");
+    }
+
+    #[test]
+    fn hostnames() {
+        assert_eq!(
+            split_hostname("ts.example.com"),
+            Some(("ts.example.com", None))
+        );
+        assert_eq!(
+            split_hostname("ts.example.com:9987"),
+            Some(("ts.example.com", Some("9987")))
+        );
+        assert_eq!(split_hostname("192.0.2.1"), None);
+        assert_eq!(split_hostname("192.0.2.1:9987"), None);
+        assert_eq!(split_hostname("2001:db8::1"), None);
+        assert_eq!(split_hostname("[2001:db8::1]:9987"), None);
+    }
+
+    #[test]
+    fn announcements() {
+        assert_eq!(announcement("Song", None), "Now playing: Song");
+        assert_eq!(
+            announcement("Song", Some("alice")),
+            "Now playing: Song (requested by alice)"
+        );
+    }
+
+    #[test]
+    fn pending_announcements() {
+        let start = Instant::now();
+        let mut pending = PendingAnnouncement::default();
+        assert_eq!(pending.take_due(start), None);
+
+        pending.schedule("first".to_string(), start + Duration::from_secs(5));
+        // Skipped before it was announced
+        pending.schedule("second".to_string(), start + Duration::from_secs(6));
+        assert_eq!(pending.deadline(), Some(start + Duration::from_secs(6)));
+        assert_eq!(pending.take_due(start + Duration::from_secs(5)), None);
+        assert_eq!(
+            pending.take_due(start + Duration::from_secs(6)),
+            Some("second".to_string())
+        );
+        assert_eq!(pending.take_due(start + Duration::from_secs(7)), None);
+
+        pending.schedule("third".to_string(), start);
+        pending.clear();
+        assert_eq!(pending.take_due(start), None);
+    }
+}
@@ -0,0 +1,212 @@
+use crate::helper::is_url;
+use crate::InfoJson;
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::BufReader;
+
+pub fn read_info_json(path: &str) -> Result<InfoJson> {
+    let file = File::open(path).with_context(|| format!("Failed to open the file: {}", path))?;
+
+    let reader = BufReader::new(file);
+
+    let info_json: InfoJson = serde_json::from_reader(reader)
+        .with_context(|| format!("Failed to parse the JSON file: {}", path))?;
+
+    Ok(info_json)
+}
+
+/// Reduces the stderr of yt-dlp to the reason of its last error, e.g. `Video unavailable`.
+pub fn ytdlp_error_message(stderr: &str) -> String {
+    stderr
+        .lines()
+        .rev()
+        .find_map(|line| line.strip_prefix("ERROR: "))
+        .map(|error| error.rsplit(": ").next().unwrap_or(error).trim())
+        .filter(|error| !error.is_empty())
+        .unwrap_or("unknown error")
+        .to_string()
+}
+
+/// yt-dlp gave up on a connection that was too slow, see `--socket-timeout`.
+pub fn is_socket_timeout(error: &str) -> bool {
+    error.to_lowercase().contains("timed out")
+}
+
+/// Network problems are worth retrying, errors like `Video unavailable` will not go away.
+pub fn is_transient_ytdlp_error(error: &str) -> bool {
+    const TRANSIENT_ERRORS: [&str; 8] = [
+        "timed out",
+        "connection",
+        "network",
+        "temporary failure",
+        "name resolution",
+        "http error 5",
+        "unable to download",
+        "incomplete",
+    ];
+
+    let error = error.to_lowercase();
+    TRANSIENT_ERRORS.iter().any(|e| error.contains(e))
+}
+
+pub async fn fetch_playlist_entries(link: &str) -> Result<Vec<String>> {
+    let output = tokio::process::Command::new("yt-dlp")
+        .args(&["--quiet", "--flat-playlist", "--print", "url", link])
+        .output()
+        .await
+        .with_context(|| format!("Failed to run yt-dlp for playlist: {}", link))?;
+
+    if !output.status.success() {
+        bail!(
+            "yt-dlp exited with status {:?} for playlist: {}",
+            output.status.code(),
+            link
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| is_url(line))
+        .map(|line| line.to_string())
+        .collect())
+}
+
+/// Resolves the metadata of a link or search term without downloading anything.
+pub async fn fetch_metadata(link: &str) -> Result<InfoJson> {
+    let target = if is_url(link) {
+        link.to_string()
+    } else {
+        format!("ytsearch1:{}", link)
+    };
+    let output = tokio::process::Command::new("yt-dlp")
+        .args(&[
+            "--quiet",
+            "--dump-json",
+            "--skip-download",
+            "--no-playlist",
+            &target,
+        ])
+        .output()
+        .await
+        .with_context(|| format!("Failed to run yt-dlp for metadata: {}", link))?;
+
+    if !output.status.success() {
+        bail!(
+            "yt-dlp exited with status {:?} for metadata: {}",
+            output.status.code(),
+            link
+        );
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let line = stdout
+        .lines()
+        .next()
+        .with_context(|| format!("yt-dlp found nothing for: {}", link))?;
+    serde_json::from_str(line).with_context(|| format!("Failed to parse metadata of: {}", link))
+}
+
+pub async fn search(query: &str, count: usize) -> Result<Vec<InfoJson>> {
+    let output = tokio::process::Command::new("yt-dlp")
+        .args(&[
+            "--quiet",
+            "--dump-json",
+            &format!("ytsearch{}:{}", count, query),
+        ])
+        .output()
+        .await
+        .with_context(|| format!("Failed to run yt-dlp for search: {}", query))?;
+
+    if !output.status.success() {
+        bail!(
+            "yt-dlp exited with status {:?} for search: {}",
+            output.status.code(),
+            query
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ytdlp_error_reason() {
+        let stderr = "WARNING: [youtube] Falling back to generic n function search\n\
+                      ERROR: [youtube] dQw4w9WgXcQ: Video unavailable\n";
+        assert_eq!(ytdlp_error_message(stderr), "Video unavailable");
+        assert_eq!(ytdlp_error_message(""), "unknown error");
+    }
+
+    #[test]
+    fn transient_ytdlp_errors() {
+        assert!(is_transient_ytdlp_error("The read operation timed out"));
+        assert!(is_transient_ytdlp_error(
+            "<urlopen error [Errno -3] Temporary failure in name resolution>"
+        ));
+        assert!(is_transient_ytdlp_error(
+            "HTTP Error 503: Service Unavailable"
+        ));
+        assert!(!is_transient_ytdlp_error("Video unavailable"));
+        assert!(!is_transient_ytdlp_error("Private video"));
+        assert!(is_socket_timeout("The read operation timed out"));
+        assert!(!is_socket_timeout("HTTP Error 503: Service Unavailable"));
+    }
+
+    #[test]
+    fn info_json_soundcloud() {
+        let info_json: InfoJson = serde_json::from_str(
+            r#"{
+                "id": "123456789",
+                "title": "Some Track",
+                "uploader": "Some Artist",
+                "duration": 213.04,
+                "view_count": 4200,
+                "webpage_url": "https://soundcloud.com/some-artist/some-track"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(info_json.artist(), "Some Artist");
+        assert_eq!(info_json.duration, 213);
+    }
+
+    #[test]
+    fn info_json_generic() {
+        let info_json: InfoJson = serde_json::from_str(
+            r#"{
+                "id": "song",
+                "title": "song",
+                "duration": null,
+                "view_count": null,
+                "webpage_url": "https://example.com/song.mp3"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(info_json.artist(), "Unknown");
+        assert_eq!(info_json.duration, 0);
+        assert!(info_json.view_count.is_none());
+        assert!(!info_json.is_live());
+    }
+
+    #[test]
+    fn info_json_livestream() {
+        let info_json: InfoJson = serde_json::from_str(
+            r#"{
+                "id": "jfKfPfyJRdk",
+                "title": "lofi hip hop radio",
+                "channel": "Lofi Girl",
+                "duration": null,
+                "is_live": true
+            }"#,
+        )
+        .unwrap();
+        assert!(info_json.is_live());
+        assert_eq!(info_json.duration, 0);
+    }
+}